@@ -6,5 +6,13 @@ extern "C" {
 }
 
 int main() {
-    full_scan("http://127.0.0.1:8080/compact.dat", "zxviews1q0duytgcqqqqpqre26wkl45gvwwwd706xw608hucmvfalr759ejwf7qshjf5r9aa7323zulvz6plhttp5mltqcgs9t039cx2d09mgq05ts63n8u35hyv6h9nc9ctqqtue2u7cer2mqegunuulq2luhq3ywjcz35yyljewa4mgkgjzyfwh6fr6jd0dzd44ghk0nxdv2hnv4j5nxfwv24rwdmgllhe0p8568sgqt9ckt02v2kxf5ahtql6s0ltjpkckw8gtymxtxuu9gcr0swvz", 0);
+    uint64_t balance = 0;
+    new_scan();
+    if (full_scan("http://127.0.0.1:8080/compact.dat", "zxviews1q0duytgcqqqqpqre26wkl45gvwwwd706xw608hucmvfalr759ejwf7qshjf5r9aa7323zulvz6plhttp5mltqcgs9t039cx2d09mgq05ts63n8u35hyv6h9nc9ctqqtue2u7cer2mqegunuulq2luhq3ywjcz35yyljewa4mgkgjzyfwh6fr6jd0dzd44ghk0nxdv2hnv4j5nxfwv24rwdmgllhe0p8568sgqt9ckt02v2kxf5ahtql6s0ltjpkckw8gtymxtxuu9gcr0swvz", NULL, 0, 0, &balance) != WARP2_OK) {
+        char *error = warp2_last_error();
+        fprintf(stderr, "%s\n", error);
+        warp2_free_string(error);
+        return 1;
+    }
+    printf("Balance = %llu\n", balance);
 }
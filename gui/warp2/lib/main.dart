@@ -40,14 +40,21 @@ class _MyHomePageState extends State<MyHomePage> {
   int balance = 0;
   int? height;
   int? elapsed;
+  String? error;
   TextEditingController url = TextEditingController(text: 'http://192.168.0.158:8080/compact.dat');
   TextEditingController fvk = TextEditingController(text: 'zxviews1q0duytgcqqqqpqre26wkl45gvwwwd706xw608hucmvfalr759ejwf7qshjf5r9aa7323zulvz6plhttp5mltqcgs9t039cx2d09mgq05ts63n8u35hyv6h9nc9ctqqtue2u7cer2mqegunuulq2luhq3ywjcz35yyljewa4mgkgjzyfwh6fr6jd0dzd44ghk0nxdv2hnv4j5nxfwv24rwdmgllhe0p8568sgqt9ckt02v2kxf5ahtql6s0ltjpkckw8gtymxtxuu9gcr0swvz');
 
   @override
   void initState() {
     super.initState();
-    syncStream.forEach((height) {
-      this.height = height;
+    syncStream.forEach((event) {
+      // progress as a list of integers that starts with the height,
+      // or an error message
+      if (event is List<int>) {
+        height = event[0];
+      } else {
+        error = event as String;
+      }
       setState(() {});
     });
   }
@@ -84,6 +91,9 @@ class _MyHomePageState extends State<MyHomePage> {
             if (elapsed != null) Text(
               'Scan duration: $elapsed secs',
             ),
+            if (error != null) Text(
+              'Error: $error',
+            ),
           ],
         ),
       ),
@@ -98,8 +108,13 @@ class _MyHomePageState extends State<MyHomePage> {
   _warp() async {
     elapsed = null;
     height = null;
+    error = null;
     final stopwatch = Stopwatch()..start();
-    balance = await WarpFFI.warp2Scan(url.text, fvk.text, syncPort.sendPort.nativePort);
+    try {
+      balance = await WarpFFI.warp2Scan(url.text, fvk.text, syncPort.sendPort.nativePort);
+    } on Exception {
+      // the error is also posted to the port
+    }
     elapsed = stopwatch.elapsed.inSeconds;
     setState(() {});
   }
@@ -33,9 +33,43 @@ class WarpFFI {
     throw UnsupportedError('This platform is not supported.');
   }
 
-  static Future<int> warp2Scan(String url, String fvk, int port) async {
+  /// Balance at the end of the scan, or where it stopped if it was cancelled
+  ///
+  /// [maxCheckpoints] is the maximum depth of a reorg, 0 for the one of the state
+  static Future<int> warp2Scan(String url, String fvk, int port,
+      {String? stateFile, int maxCheckpoints = 0}) async {
+    // before the scan starts, so that it can be cancelled right away
+    checkStatus(warp2_lib.new_scan());
     return await compute((_) {
-      return warp2_lib.full_scan(toNative(url), toNative(fvk), port);
+      final balance = calloc<Uint64>();
+      try {
+        final state = stateFile != null ? toNative(stateFile) : nullptr.cast<Int8>();
+        final status = warp2_lib.full_scan(toNative(url), toNative(fvk), state, port, maxCheckpoints, balance);
+        if (status != WARP2_CANCELLED) checkStatus(status);
+        return balance.value;
+      } finally {
+        calloc.free(balance);
+      }
     }, null);
   }
+
+  static void cancelScan() {
+    checkStatus(warp2_lib.cancel_scan());
+  }
+
+  static void pauseScan() {
+    checkStatus(warp2_lib.pause_scan());
+  }
+
+  static void resumeScan() {
+    checkStatus(warp2_lib.resume_scan());
+  }
+
+  static void checkStatus(int status) {
+    if (status == WARP2_OK) return;
+    final error = warp2_lib.warp2_last_error();
+    final message = error.cast<Utf8>().toDartString();
+    warp2_lib.warp2_free_string(error);
+    throw Exception(message);
+  }
 }
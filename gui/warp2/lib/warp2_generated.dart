@@ -18,7 +18,7 @@ class NativeLibrary {
           lookup)
       : _lookup = lookup;
 
-  void dart_post_cobject(
+  int dart_post_cobject(
     ffi.Pointer<ffi.Void> ptr,
   ) {
     return _dart_post_cobject(
@@ -31,15 +31,30 @@ class NativeLibrary {
   late final _dart_dart_post_cobject _dart_post_cobject =
       _dart_post_cobject_ptr.asFunction<_dart_dart_post_cobject>();
 
+  int new_scan() {
+    return _new_scan();
+  }
+
+  late final _new_scan_ptr =
+      _lookup<ffi.NativeFunction<_c_new_scan>>('new_scan');
+  late final _dart_new_scan _new_scan =
+      _new_scan_ptr.asFunction<_dart_new_scan>();
+
   int full_scan(
     ffi.Pointer<ffi.Int8> url,
     ffi.Pointer<ffi.Int8> fvk,
+    ffi.Pointer<ffi.Int8> state_file,
     int port,
+    int max_checkpoints,
+    ffi.Pointer<ffi.Uint64> balance,
   ) {
     return _full_scan(
       url,
       fvk,
+      state_file,
       port,
+      max_checkpoints,
+      balance,
     );
   }
 
@@ -47,26 +62,117 @@ class NativeLibrary {
       _lookup<ffi.NativeFunction<_c_full_scan>>('full_scan');
   late final _dart_full_scan _full_scan =
       _full_scan_ptr.asFunction<_dart_full_scan>();
+
+  int cancel_scan() {
+    return _cancel_scan();
+  }
+
+  late final _cancel_scan_ptr =
+      _lookup<ffi.NativeFunction<_c_cancel_scan>>('cancel_scan');
+  late final _dart_cancel_scan _cancel_scan =
+      _cancel_scan_ptr.asFunction<_dart_cancel_scan>();
+
+  int pause_scan() {
+    return _pause_scan();
+  }
+
+  late final _pause_scan_ptr =
+      _lookup<ffi.NativeFunction<_c_pause_scan>>('pause_scan');
+  late final _dart_pause_scan _pause_scan =
+      _pause_scan_ptr.asFunction<_dart_pause_scan>();
+
+  int resume_scan() {
+    return _resume_scan();
+  }
+
+  late final _resume_scan_ptr =
+      _lookup<ffi.NativeFunction<_c_resume_scan>>('resume_scan');
+  late final _dart_resume_scan _resume_scan =
+      _resume_scan_ptr.asFunction<_dart_resume_scan>();
+
+  ffi.Pointer<ffi.Int8> warp2_last_error() {
+    return _warp2_last_error();
+  }
+
+  late final _warp2_last_error_ptr =
+      _lookup<ffi.NativeFunction<_c_warp2_last_error>>('warp2_last_error');
+  late final _dart_warp2_last_error _warp2_last_error =
+      _warp2_last_error_ptr.asFunction<_dart_warp2_last_error>();
+
+  void warp2_free_string(
+    ffi.Pointer<ffi.Int8> s,
+  ) {
+    return _warp2_free_string(
+      s,
+    );
+  }
+
+  late final _warp2_free_string_ptr =
+      _lookup<ffi.NativeFunction<_c_warp2_free_string>>('warp2_free_string');
+  late final _dart_warp2_free_string _warp2_free_string =
+      _warp2_free_string_ptr.asFunction<_dart_warp2_free_string>();
 }
 
 const int DEPTH = 32;
 
-typedef _c_dart_post_cobject = ffi.Void Function(
+const int WARP2_OK = 0;
+
+const int WARP2_CANCELLED = 1;
+
+const int WARP2_ERROR = -1;
+
+const int WARP2_PANIC = -2;
+
+typedef _c_dart_post_cobject = ffi.Int32 Function(
   ffi.Pointer<ffi.Void> ptr,
 );
 
-typedef _dart_dart_post_cobject = void Function(
+typedef _dart_dart_post_cobject = int Function(
   ffi.Pointer<ffi.Void> ptr,
 );
 
-typedef _c_full_scan = ffi.Uint64 Function(
+typedef _c_new_scan = ffi.Int32 Function();
+
+typedef _dart_new_scan = int Function();
+
+typedef _c_full_scan = ffi.Int32 Function(
   ffi.Pointer<ffi.Int8> url,
   ffi.Pointer<ffi.Int8> fvk,
+  ffi.Pointer<ffi.Int8> state_file,
   ffi.Int64 port,
+  ffi.Uint32 max_checkpoints,
+  ffi.Pointer<ffi.Uint64> balance,
 );
 
 typedef _dart_full_scan = int Function(
   ffi.Pointer<ffi.Int8> url,
   ffi.Pointer<ffi.Int8> fvk,
+  ffi.Pointer<ffi.Int8> state_file,
   int port,
+  int max_checkpoints,
+  ffi.Pointer<ffi.Uint64> balance,
+);
+
+typedef _c_cancel_scan = ffi.Int32 Function();
+
+typedef _dart_cancel_scan = int Function();
+
+typedef _c_pause_scan = ffi.Int32 Function();
+
+typedef _dart_pause_scan = int Function();
+
+typedef _c_resume_scan = ffi.Int32 Function();
+
+typedef _dart_resume_scan = int Function();
+
+typedef _c_warp2_last_error = ffi.Pointer<ffi.Int8> Function();
+
+typedef _dart_warp2_last_error = ffi.Pointer<ffi.Int8> Function();
+
+typedef _c_warp2_free_string = ffi.Void Function(
+  ffi.Pointer<ffi.Int8> s,
+);
+
+typedef _dart_warp2_free_string = void Function(
+  ffi.Pointer<ffi.Int8> s,
 );
@@ -1,18 +1,231 @@
-use std::ffi::{c_char, CStr};
+use std::any::Any;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use allo_isolate::ffi;
+use allo_isolate::IntoDart;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
 use zcash_primitives::consensus::Network::MainNetwork;
+use crate::warp::scan::ScanHandle;
+
+/// The call succeeded
+pub const WARP2_OK: i32 = 0;
+/// The scan was stopped by [cancel_scan]
+pub const WARP2_CANCELLED: i32 = 1;
+/// The call failed, [warp2_last_error] has the reason
+pub const WARP2_ERROR: i32 = -1;
+/// The call panicked, [warp2_last_error] has the panic message
+pub const WARP2_PANIC: i32 = -2;
 
 pub static mut POST_COBJ: Option<ffi::DartPostCObjectFnType> = None;
 
+/// The handle was created by [new_scan] and no scan has used it yet
+static SCAN_READY: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Handle of the last scan created by [new_scan]
+    static ref SCAN_HANDLE: Mutex<ScanHandle> = Mutex::new(ScanHandle::default());
+    /// Message of the last call that failed
+    static ref LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// # Safety
+/// `ptr` must be the `NativeApi.postCObject` function pointer handed over by Dart
+#[no_mangle]
+pub unsafe extern "C" fn dart_post_cobject(ptr: ffi::DartPostCObjectFnType) -> i32 {
+    ffi_call(0, || {
+        POST_COBJ = Some(ptr);
+        Ok(())
+    })
+}
+
+/// Create the handle of the next scan
+///
+/// [cancel_scan], [pause_scan] and [resume_scan] apply to the scan as soon
+/// as this returns, even if [full_scan] has not started yet. Without it,
+/// [full_scan] starts with a new handle
+#[no_mangle]
+pub extern "C" fn new_scan() -> i32 {
+    ffi_call(0, || {
+        let mut handle = scan_handle();
+        *handle = ScanHandle::default();
+        SCAN_READY.store(true, Ordering::Release);
+        Ok(())
+    })
+}
+
+/// Scan the data file at `url` with the viewing key `fvk` and store the
+/// balance in `balance`
+///
+/// The scan is controlled by the handle created by [new_scan], or by a new
+/// one if the handle was already used by a previous scan. It returns
+/// [WARP2_CANCELLED] if it was cancelled, with the balance at the height
+/// where it stopped
+///
+/// If `state_file` is not NULL, the scan resumes from the state saved there
+/// and saves it after every chunk of blocks, so that a cancelled scan
+/// continues where it stopped
+///
+/// The last `max_checkpoints` blocks are kept as checkpoints, i.e. the
+/// maximum depth of a reorg and of an anchor. 0 keeps the window of the
+/// state file, 100 blocks for a new state
+///
+/// The progress is posted to `port` after every chunk of blocks as a list of
+/// integers: height, target height, block bytes, blocks, outputs, notes, spends,
+/// elapsed and ETA in ms (-1 when unknown). If the scan fails, the error
+/// message is posted as a string
+///
+/// # Safety
+/// `url` and `fvk` must be valid NUL terminated C strings, `state_file`
+/// must be NULL or a valid NUL terminated C string and `balance`
+/// must be NULL or point to a writable u64
+#[no_mangle]
+pub unsafe extern "C" fn full_scan(
+    url: *const c_char,
+    fvk: *const c_char,
+    state_file: *const c_char,
+    port: i64,
+    max_checkpoints: u32,
+    balance: *mut u64,
+) -> i32 {
+    let mut cancelled = false;
+    let status = ffi_call(port, || {
+        let url = from_c_str(url)?;
+        let fvk = from_c_str(fvk)?;
+        let state_file = if state_file.is_null() {
+            None
+        } else {
+            Some(from_c_str(state_file)?)
+        };
+        let mut handle = {
+            let mut handle = scan_handle();
+            // the handle of the previous scan stays cancelled
+            if !SCAN_READY.swap(false, Ordering::AcqRel) {
+                *handle = ScanHandle::default();
+            }
+            handle.with_port(port)
+        };
+        if max_checkpoints > 0 {
+            handle = handle.with_max_checkpoints(max_checkpoints as usize);
+        }
+        let result = crate::warp::scan::full_scan(
+            &MainNetwork,
+            &url,
+            &fvk,
+            state_file.as_deref(),
+            &handle,
+        )?;
+        if !balance.is_null() {
+            *balance = result.balance;
+        }
+        cancelled = result.cancelled;
+        Ok(())
+    });
+    if status == WARP2_OK && cancelled {
+        WARP2_CANCELLED
+    } else {
+        status
+    }
+}
+
+/// Stop the running scan at the end of its current chunk of blocks.
+/// [full_scan] then stores the balance at that height and returns
+/// [WARP2_CANCELLED]
+#[no_mangle]
+pub extern "C" fn cancel_scan() -> i32 {
+    ffi_call(0, || {
+        scan_handle().cancel();
+        Ok(())
+    })
+}
+
+/// Pause the running scan at the end of its current chunk of blocks
 #[no_mangle]
-pub unsafe extern "C" fn dart_post_cobject(ptr: ffi::DartPostCObjectFnType) {
-    POST_COBJ = Some(ptr);
+pub extern "C" fn pause_scan() -> i32 {
+    ffi_call(0, || {
+        scan_handle().pause();
+        Ok(())
+    })
 }
 
-#[tokio::main]
 #[no_mangle]
-pub async unsafe extern "C" fn full_scan(url: *mut c_char, fvk: *mut c_char, port: i64) -> u64 {
-    let url = CStr::from_ptr(url).to_string_lossy();
-    let fvk = CStr::from_ptr(fvk).to_string_lossy();
-    crate::warp::scan::full_scan(&MainNetwork, &url, &fvk, port).await.unwrap()
+pub extern "C" fn resume_scan() -> i32 {
+    ffi_call(0, || {
+        scan_handle().resume();
+        Ok(())
+    })
+}
+
+/// Message of the last call that did not return [WARP2_OK], NULL if none.
+/// The string must be released with [warp2_free_string]
+#[no_mangle]
+pub extern "C" fn warp2_last_error() -> *mut c_char {
+    let error = LAST_ERROR
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    match error {
+        // NULs are removed by set_last_error
+        Some(error) => CString::new(error).unwrap_or_default().into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `s` must be NULL or a string returned by [warp2_last_error]
+#[no_mangle]
+pub unsafe extern "C" fn warp2_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Run `f`, catching its errors and panics. They are saved for
+/// [warp2_last_error] and posted to `port` if it is not 0
+fn ffi_call<F: FnOnce() -> Result<()>>(port: i64, f: F) -> i32 {
+    let (status, error) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return WARP2_OK,
+        Ok(Err(e)) => (WARP2_ERROR, format!("{e:#}")),
+        Err(panic) => (WARP2_PANIC, panic_message(panic)),
+    };
+    log::error!("{error}");
+    set_last_error(port, error);
+    status
+}
+
+fn set_last_error(port: i64, error: String) {
+    let error = error.replace('\0', " ");
+    if port != 0 {
+        unsafe {
+            if let Some(post) = POST_COBJ {
+                post(port, &mut error.clone().into_dart());
+            }
+        }
+    }
+    *LAST_ERROR.lock().unwrap_or_else(PoisonError::into_inner) = Some(error);
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "Unknown panic".to_string(),
+        },
+    };
+    format!("Panic: {message}")
+}
+
+/// The handle of the scan, even if a previous call panicked while holding it
+fn scan_handle() -> MutexGuard<'static, ScanHandle> {
+    SCAN_HANDLE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+unsafe fn from_c_str(s: *const c_char) -> Result<String> {
+    if s.is_null() {
+        return Err(anyhow!("NULL string"));
+    }
+    Ok(CStr::from_ptr(s).to_str()?.to_string())
 }
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::BufWriter;
+use warp2::warp::producer::produce;
+use warp2::warp::source::{BlockSource, LwdSource, PlainFileSource};
+use zcash_primitives::consensus::{Network, NetworkUpgrade, Parameters};
+
+/// Convert plain compact blocks into a warp data file
+///
+/// Usage: warp2-producer <SOURCE> <OUTPUT> [SPAM_THRESHOLD]
+///
+/// SOURCE is a data file of plain compact blocks starting at the activation
+/// of sapling, or the URL of a lightwalletd server prefixed with `lwd+`
+fn main() -> Result<()> {
+    let mut args = std::env::args();
+    let source = args
+        .nth(1)
+        .expect("Need the source of the blocks as an argument");
+    let output = args.next().expect("Need the output file as an argument");
+    let spam_threshold = match args.next() {
+        Some(threshold) => threshold.parse()?,
+        None => 50,
+    };
+    let source: Box<dyn BlockSource> = match source.strip_prefix("lwd+") {
+        Some(lwd_url) => {
+            let start_height = Network::MainNetwork
+                .activation_height(NetworkUpgrade::Sapling)
+                .unwrap();
+            // the server must not bridge anything
+            Box::new(LwdSource::new(lwd_url, start_height.into(), None, 0)?)
+        }
+        None => Box::new(PlainFileSource::open(&source)?),
+    };
+    let w = BufWriter::new(File::create(&output)?);
+    let header = produce(source, w, Network::MainNetwork, spam_threshold)?;
+    println!(
+        "Heights {}-{}, sapling tree size = {}, orchard tree size = {}",
+        header.start_height, header.end_height, header.sapling.pos, header.orchard.pos
+    );
+    Ok(())
+}
@@ -1,13 +1,126 @@
 use anyhow::Result;
+use std::sync::Arc;
+use warp2::warp::keys::Account;
+use warp2::warp::lwd::connect_lightwalletd;
+use warp2::warp::memo::{fetch_memos, fetch_sent_outputs};
+use warp2::warp::progress::ScanProgress;
+use warp2::warp::scan::{PoolResult, ScanHandle};
+use warp2::warp::source::LwdTransactionSource;
+use warp2::warp::Hasher;
 use zcash_primitives::consensus::Network;
+use zcash_primitives::memo::Memo;
+
+/// Transactions with more inputs/outputs/actions are bridged by lightwalletd
+const SPAM_THRESHOLD: u64 = 50;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut args = std::env::args();
     let url = args.nth(1).expect("Need the data file URL as an argument");
-    let fvk = args.nth(0).expect("Need the FVK as an argument");
-    warp2::warp::scan::full_scan(&Network::MainNetwork, &url, &fvk, 0).await?;
+    let fvks = args.next().expect("Need the FVK as an argument");
+    let state_file = args.next();
+    let accounts = fvks
+        .split(',')
+        .enumerate()
+        .map(|(i, key)| {
+            if key.contains(' ') {
+                // seed phrase
+                Account::from_seed(&Network::MainNetwork, i as u32, key)
+            } else {
+                Account::decode(&Network::MainNetwork, i as u32, key)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let handle = ScanHandle::with_listener(0, Arc::new(print_progress));
+    let result = match url.strip_prefix("lwd+") {
+        Some(lwd_url) => {
+            let mut result = warp2::warp::scan::scan_lightwalletd(
+                &Network::MainNetwork,
+                lwd_url,
+                &accounts,
+                None,
+                SPAM_THRESHOLD,
+                state_file.as_deref(),
+                &handle,
+            )
+            .await?;
+            let client = connect_lightwalletd(lwd_url).await?;
+            let mut source = LwdTransactionSource::new(client);
+            fetch_memos(&Network::MainNetwork, &accounts, &mut source, &mut result).await?;
+            fetch_sent_outputs(&Network::MainNetwork, &accounts, &mut source, &mut result).await?;
+            result
+        }
+        None => warp2::warp::scan::scan_accounts(
+            &Network::MainNetwork,
+            &url,
+            &accounts,
+            state_file.as_deref(),
+            &handle,
+        )?,
+    };
+    println!("Final height = {}", result.height);
+    print_pool("Sapling", &result.sapling);
+    print_pool("Orchard", &result.orchard);
+    for u in result.transparent.utxos.iter() {
+        println!(
+            "Transparent UTXO {}:{} = {} (address #{} {:?})",
+            hex::encode(u.txid),
+            u.vout,
+            u.value,
+            u.address_index,
+            u.scope
+        );
+    }
+    for u in result.transparent.spent_utxos.iter() {
+        println!("Transparent spent {} at {}", u.utxo.value, u.spent_height);
+    }
+    for a in accounts.iter() {
+        println!(
+            "Account #{} balance = {}",
+            a.id,
+            result.account_balance(a.id)
+        );
+    }
+    println!("Balance = {}", result.balance);
 
     Ok(())
 }
 
+fn print_progress(p: &ScanProgress) {
+    let target = p.target_height.map(|h| h.to_string()).unwrap_or_default();
+    let eta = p
+        .eta
+        .map(|eta| format!(", ETA {}s", eta.as_secs()))
+        .unwrap_or_default();
+    println!(
+        "\x1b[0;31mHeight: {}/{target}\x1b[0m {} blocks, {} outputs, {} notes, {} spends, {} MB in {}s{eta}",
+        p.height,
+        p.blocks,
+        p.outputs,
+        p.notes,
+        p.spends,
+        p.block_bytes / 1_000_000,
+        p.elapsed.as_secs(),
+    );
+}
+
+fn print_pool<H: Hasher>(name: &str, result: &PoolResult<H>) {
+    println!("{name} anchor = {}", hex::encode(result.anchor));
+    for (i, n) in result.notes.iter().enumerate() {
+        println!(
+            "{name} note #{i} / {} = {} ({:?})",
+            n.note.position, n.note.value, n.note.scope
+        );
+        if let Some(memo) = n.note.memo.as_ref() {
+            if let Ok(Memo::Text(text)) = Memo::try_from(memo.clone()) {
+                println!("{name} note #{i} memo: {}", &*text);
+            }
+        }
+    }
+    for n in result.spent_notes.iter() {
+        println!("{name} spent {} at {}", n.note.value, n.spent_height);
+    }
+    for o in result.sent_outputs.iter().filter(|o| !o.change) {
+        println!("{name} sent {} to {} at {}", o.value, o.address, o.height);
+    }
+}
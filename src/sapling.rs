@@ -7,7 +7,7 @@ lazy_static! {
     pub static ref GENERATORS_EXP: Vec<ExtendedNielsPoint> = read_generators_bin();
 }
 
-pub const GENERATORS: &'static [u8] = include_bytes!("sapling/generators.bin");
+pub const GENERATORS: &[u8] = include_bytes!("sapling/generators.bin");
 
 pub mod hash;
 
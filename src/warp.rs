@@ -16,9 +16,16 @@ pub trait ReadWrite {
 }
 
 pub trait Hasher: Debug + Default {
+    /// Identifies the hasher in serialized trees
+    const ID: u8;
     type D: Copy + Clone + PartialEq + Default + Debug + ReadWrite;
     fn empty(&self) -> Self::D;
     fn is_empty(&self, d: &Self::D) -> bool;
+    /// Whether `d` is the canonical encoding of a node
+    ///
+    /// The nodes that come from data files and lightwalletd are checked
+    /// before they are hashed
+    fn is_valid(&self, d: &Self::D) -> bool;
     fn combine(&self, depth: u8, l: &Self::D, r: &Self::D, check: bool) -> Self::D;
     fn parallel_combine(&self, depth: u8, layer: &[Self::D], pairs: usize) -> Vec<Self::D>;
 }
@@ -37,8 +44,23 @@ impl ReadWrite for Hash {
 }
 
 pub mod bridge;
-pub mod scan;
+pub mod data;
+pub mod frontier;
 pub mod hasher;
+pub mod keys;
+pub mod lwd;
+pub mod memo;
+pub mod mempool;
+pub mod note;
+pub mod producer;
+pub mod progress;
+pub mod scan;
+pub mod service;
+pub mod source;
+pub mod state;
+#[cfg(test)]
+mod testing;
+pub mod transparent;
 pub mod tree;
 pub mod witness;
 
@@ -48,6 +70,16 @@ pub struct Path<H: Hasher> {
     pub siblings: Vec<H::D>,
 }
 
+impl<H: Hasher> Clone for Path<H> {
+    fn clone(&self) -> Self {
+        Path {
+            value: self.value,
+            pos: self.pos,
+            siblings: self.siblings.clone(),
+        }
+    }
+}
+
 impl<H: Hasher> Debug for Path<H> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "value {:?}", self.value)?;
@@ -68,22 +100,32 @@ impl<H: Hasher> Path<H> {
     fn write<W: Write>(&self, mut w: W) -> Result<()> {
         w.write_u64::<LE>(self.pos as u64)?;
         self.value.write(&mut w)?;
-        w.write_u8(self.siblings.len() as u8)?;
+        w.write_u32::<LE>(self.siblings.len() as u32)?;
         for s in self.siblings.iter() {
             s.write(&mut w)?;
         }
         Ok(())
     }
 
-    fn read<R: Read>(mut r: R) -> Result<Self> {
-        let pos = r.read_u64::<LE>()? as usize;
+    fn read<R: Read>(mut r: R, h: &H) -> Result<Self> {
+        let pos = r.read_u64::<LE>()?;
+        if pos >= 1 << DEPTH {
+            anyhow::bail!("Invalid position {pos}");
+        }
+        let pos = pos as usize;
         let value = H::D::read(&mut r)?;
-        let len = r.read_u8()? as usize;
+        // there is a left sibling for every 1 bit of the position
+        let len = r.read_u32::<LE>()? as usize;
+        if len != pos.count_ones() as usize {
+            anyhow::bail!("Invalid number of siblings {len} at position {pos}");
+        }
         let mut siblings = vec![];
         for _ in 0..len {
             let s = H::D::read(&mut r)?;
             siblings.push(s);
         }
+        hasher::check_nodes(h, &[value])?;
+        hasher::check_nodes(h, &siblings)?;
         Ok(Self {
             value,
             pos,
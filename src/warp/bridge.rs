@@ -11,7 +11,7 @@ pub struct CompactLayer<H: Hasher> {
 }
 
 pub fn write_data<H: Hasher, W: Write>(data: &H::D, mut w: W, h: &H) -> Result<()> {
-    if h.is_empty(&data) {
+    if h.is_empty(data) {
         w.write_u8(0)?;
     } else {
         w.write_u8(1)?;
@@ -25,10 +25,19 @@ pub fn read_data<H: Hasher, R: Read>(mut r: R, h: &H) -> Result<H::D> {
     if is_empty == 0 {
         Ok(h.empty())
     } else {
-        Ok(H::D::read(&mut r)?)
+        read_node(&mut r, h)
     }
 }
 
+/// Read a node and check that it is canonical
+fn read_node<H: Hasher, R: Read>(r: R, h: &H) -> Result<H::D> {
+    let node = H::D::read(r)?;
+    if !h.is_valid(&node) {
+        anyhow::bail!("Invalid node {node:?} in bridge");
+    }
+    Ok(node)
+}
+
 impl<H: Hasher> CompactLayer<H> {
     pub fn write<W: Write>(&self, mut w: W, h: &H) -> Result<()> {
         write_data(&self.fill, &mut w, h)?;
@@ -50,17 +59,26 @@ pub struct Bridge<H: Hasher> {
     pub pos: usize,
     pub len: usize,
     pub layers: [CompactLayer<H>; DEPTH],
+    /// Last leaf of the tree after the bridge
+    ///
+    /// The bridges made by lightwalletd do not have it nor the ommers
+    pub leaf: Option<H::D>,
+    /// Ommers of the tree after the bridge that are combined into
+    /// the `prev` of the layers, see [super::MerkleTree::ommers]
+    pub ommers: Vec<H::D>,
 }
 
 impl<H: Hasher> Bridge<H> {
     pub fn merge(&mut self, other: &Bridge<H>, h: &H) {
         for i in 0..DEPTH {
             if h.is_empty(&self.layers[i].fill) && !h.is_empty(&other.layers[i].fill) {
-                self.layers[i].fill = other.layers[i].fill.clone();
+                self.layers[i].fill = other.layers[i].fill;
             }
-            self.layers[i].prev = other.layers[i].prev.clone();
+            self.layers[i].prev = other.layers[i].prev;
         }
         self.len += other.len;
+        self.leaf = other.leaf;
+        self.ommers = other.ommers.clone();
     }
 
     pub fn write<W: Write>(&self, mut w: W, h: &H) -> Result<()> {
@@ -71,6 +89,13 @@ impl<H: Hasher> Bridge<H> {
         for layer in self.layers.iter() {
             layer.write(&mut w, h)?;
         }
+        if let Some(leaf) = self.leaf.as_ref() {
+            leaf.write(&mut w)?;
+            w.write_u8(self.ommers.len() as u8)?;
+            for o in self.ommers.iter() {
+                o.write(&mut w)?;
+            }
+        }
         Ok(())
     }
 
@@ -84,12 +109,33 @@ impl<H: Hasher> Bridge<H> {
             let layer = CompactLayer::read(&mut r, h)?;
             layers.push(layer);
         }
+        // the leaf and the ommers are optional trailing fields
+        let mut rest = vec![];
+        r.read_to_end(&mut rest)?;
+        let mut leaf = None;
+        let mut ommers = vec![];
+        if !rest.is_empty() {
+            let mut r = &*rest;
+            leaf = Some(read_node(&mut r, h)?);
+            let count = r.read_u8()? as usize;
+            if count > DEPTH {
+                anyhow::bail!("Invalid number of ommers {count}");
+            }
+            for _ in 0..count {
+                ommers.push(read_node(&mut r, h)?);
+            }
+            if !r.is_empty() {
+                anyhow::bail!("{} extra bytes after the bridge", r.len());
+            }
+        }
         Ok(Bridge {
             pos: 0,
             len,
             height: 0,
             block_len: 0,
             layers: layers.try_into().unwrap(),
+            leaf,
+            ommers,
         })
     }
 
@@ -103,6 +149,8 @@ impl<H: Hasher> Bridge<H> {
                 fill: h.empty(),
                 prev: h.empty(),
             }),
+            leaf: Some(h.empty()),
+            ommers: vec![],
         }
     }
 }
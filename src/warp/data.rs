@@ -0,0 +1,762 @@
+//! Warp data file
+//!
+//! Layout:
+//! - header, see [DataFileHeader]
+//! - segments of up to [SEGMENT_LEN] blocks: block count (u32), blocks as
+//!   u32 length + protobuf CompactBlock, checksum of the count and blocks
+//! - end marker: block count = 0
+//! - index: segment count (u32), then for every segment its first height,
+//!   offset and checksum, checksum of the count and segments
+//! - trailer: offset of the index (u64), [MAGIC]
+//!
+//! All the integers are little endian
+
+use super::source::BlockSource;
+use super::tree::combined_ommers;
+use super::{Hash, Hasher, MerkleTree, ReadWrite, DEPTH};
+use crate::lw_rpc::CompactBlock;
+use anyhow::{anyhow, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use prost::Message;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use zcash_primitives::consensus::Network;
+
+pub const MAGIC: &[u8; 4] = b"WRP2";
+pub const VERSION: u16 = 1;
+/// Number of blocks per segment
+pub const SEGMENT_LEN: usize = 1000;
+pub const HEADER_LEN: usize = 4 + 2 + 1 + 3 * 4 + 2 * TreeFrontier::LEN + 32;
+pub const TRAILER_LEN: usize = 8 + 4;
+/// Largest block accepted, a compact block of a full 2 MB block is smaller
+pub(super) const MAX_BLOCK_LEN: u32 = 16 * 1024 * 1024;
+
+fn checksum_params() -> blake2b_simd::Params {
+    let mut params = blake2b_simd::Params::new();
+    params.hash_length(32).personal(b"Warp2_Checksum__");
+    params
+}
+
+fn checksum(data: &[u8]) -> Hash {
+    checksum_params().hash(data).as_bytes().try_into().unwrap()
+}
+
+/// Map an unexpected end of file to a clearer error
+fn truncated(e: std::io::Error) -> anyhow::Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        anyhow!("Data file is truncated")
+    } else {
+        e.into()
+    }
+}
+
+/// Position and frontier of a commitment tree, with the last leaf and
+/// the ommers needed by [MerkleTree::to_frontier]
+#[derive(Clone, Debug)]
+pub struct TreeFrontier {
+    pub pos: u64,
+    pub prev: [Hash; DEPTH + 1],
+    pub leaf: Hash,
+    pub ommers: [Hash; DEPTH],
+}
+
+impl TreeFrontier {
+    const LEN: usize = 8 + (DEPTH + 1) * 32 + 32 + DEPTH * 32;
+
+    /// Fail if the last leaf and the ommers of `tree` are unknown
+    pub fn from_tree<H: Hasher<D = Hash>>(tree: &MerkleTree<H>) -> Result<Self> {
+        let Some(leaf) = tree.leaf else {
+            anyhow::bail!("The frontier of the tree of size {} is unknown", tree.pos);
+        };
+        Ok(TreeFrontier {
+            pos: tree.pos as u64,
+            prev: tree.prev,
+            leaf,
+            ommers: tree.ommers,
+        })
+    }
+
+    /// Fail if `tree` does not have this frontier
+    ///
+    /// Only the ommers that are combined into `prev` are compared,
+    /// the others are not part of the frontier. They are not compared
+    /// either when the tree does not know them
+    pub fn check<H: Hasher<D = Hash>>(&self, tree: &MerkleTree<H>) -> Result<()> {
+        let combined = combined_ommers(tree.pos);
+        let frontier_matches = match tree.leaf {
+            Some(leaf) => {
+                (tree.pos == 0 || self.leaf == leaf)
+                    && self.ommers[..combined] == tree.ommers[..combined]
+            }
+            None => true,
+        };
+        if self.pos != tree.pos as u64 || self.prev != tree.prev || !frontier_matches {
+            anyhow::bail!(
+                "The tree of size {} does not match the frontier of size {}",
+                tree.pos,
+                self.pos
+            );
+        }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u64::<LE>(self.pos)?;
+        for p in self.prev.iter() {
+            p.write(&mut w)?;
+        }
+        self.leaf.write(&mut w)?;
+        for o in self.ommers.iter() {
+            o.write(&mut w)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let pos = r.read_u64::<LE>()?;
+        let mut prev = [[0u8; 32]; DEPTH + 1];
+        for p in prev.iter_mut() {
+            *p = Hash::read(&mut r)?;
+        }
+        let leaf = Hash::read(&mut r)?;
+        let mut ommers = [[0u8; 32]; DEPTH];
+        for o in ommers.iter_mut() {
+            *o = Hash::read(&mut r)?;
+        }
+        Ok(TreeFrontier {
+            pos,
+            prev,
+            leaf,
+            ommers,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DataFileHeader {
+    pub network: Network,
+    pub start_height: u32,
+    pub end_height: u32,
+    /// Transactions with more inputs/outputs/actions are bridged
+    pub spam_threshold: u32,
+    /// Commitment trees after the block at `end_height`
+    pub sapling: TreeFrontier,
+    pub orchard: TreeFrontier,
+}
+
+impl DataFileHeader {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        let mut data = vec![];
+        data.write_all(MAGIC)?;
+        data.write_u16::<LE>(VERSION)?;
+        data.write_u8(match self.network {
+            Network::MainNetwork => 0,
+            Network::TestNetwork => 1,
+        })?;
+        data.write_u32::<LE>(self.start_height)?;
+        data.write_u32::<LE>(self.end_height)?;
+        data.write_u32::<LE>(self.spam_threshold)?;
+        self.sapling.write(&mut data)?;
+        self.orchard.write(&mut data)?;
+        let cks = checksum(&data);
+        w.write_all(&data)?;
+        w.write_all(&cks)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut data = [0u8; HEADER_LEN];
+        r.read_exact(&mut data).map_err(truncated)?;
+        if &data[0..4] != MAGIC {
+            anyhow::bail!("Not a warp2 data file");
+        }
+        let (data, cks) = data.split_at(HEADER_LEN - 32);
+        let mut r = &data[4..];
+        let version = r.read_u16::<LE>()?;
+        if version != VERSION {
+            anyhow::bail!("Unsupported data file version {version}");
+        }
+        if checksum(data) != cks {
+            anyhow::bail!("Corrupted data file header");
+        }
+        let network = match r.read_u8()? {
+            0 => Network::MainNetwork,
+            1 => Network::TestNetwork,
+            n => anyhow::bail!("Invalid network {n}"),
+        };
+        let start_height = r.read_u32::<LE>()?;
+        let end_height = r.read_u32::<LE>()?;
+        let spam_threshold = r.read_u32::<LE>()?;
+        let sapling = TreeFrontier::read(&mut r)?;
+        let orchard = TreeFrontier::read(&mut r)?;
+        Ok(DataFileHeader {
+            network,
+            start_height,
+            end_height,
+            spam_threshold,
+            sapling,
+            orchard,
+        })
+    }
+
+    /// Fail if the data file is for another network
+    pub fn check_network(&self, network: &Network) -> Result<()> {
+        if self.network != *network {
+            anyhow::bail!("Data file is for {:?}, not {:?}", self.network, network);
+        }
+        Ok(())
+    }
+}
+
+/// Index entry of a segment
+#[derive(Clone, Debug)]
+pub struct SegmentIndex {
+    /// Height of the first block
+    pub height: u32,
+    pub offset: u64,
+    pub checksum: Hash,
+}
+
+impl SegmentIndex {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LE>(self.height)?;
+        w.write_u64::<LE>(self.offset)?;
+        self.checksum.write(&mut w)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let height = r.read_u32::<LE>()?;
+        let offset = r.read_u64::<LE>()?;
+        let checksum = Hash::read(&mut r)?;
+        Ok(SegmentIndex {
+            height,
+            offset,
+            checksum,
+        })
+    }
+}
+
+/// Segments of a data file by increasing height
+#[derive(Clone, Debug)]
+pub struct DataFileIndex {
+    pub segments: Vec<SegmentIndex>,
+}
+
+impl DataFileIndex {
+    /// Read the offset of the index from the trailer
+    pub fn read_trailer<R: Read>(mut r: R) -> Result<u64> {
+        let offset = r.read_u64::<LE>().map_err(truncated)?;
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            anyhow::bail!("Data file has no index");
+        }
+        Ok(offset)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        data.write_u32::<LE>(self.segments.len() as u32)?;
+        for s in self.segments.iter() {
+            s.write(&mut data)?;
+        }
+        Ok(data)
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        let data = self.encode()?;
+        w.write_all(&data)?;
+        w.write_all(&checksum(&data))?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let len = r.read_u32::<LE>().map_err(truncated)? as usize;
+        let mut segments = vec![];
+        for _ in 0..len {
+            segments.push(SegmentIndex::read(&mut r)?);
+        }
+        let mut cks = [0u8; 32];
+        r.read_exact(&mut cks).map_err(truncated)?;
+        let index = DataFileIndex { segments };
+        if checksum(&index.encode()?) != cks {
+            anyhow::bail!("Corrupted data file index");
+        }
+        Ok(index)
+    }
+
+    /// Read the index at the end of the data file
+    pub fn load<R: Read + Seek>(mut r: R) -> Result<Self> {
+        r.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        let offset = Self::read_trailer(&mut r)?;
+        r.seek(SeekFrom::Start(offset))?;
+        Self::read(&mut r)
+    }
+
+    /// The segment that contains the block at `height`,
+    /// or the first segment if `height` is before the start of the file
+    pub fn find(&self, height: u32) -> Result<&SegmentIndex> {
+        Ok(&self.segments_from(height)?[0])
+    }
+
+    /// The segments from the one that contains the block at `height`
+    pub fn segments_from(&self, height: u32) -> Result<&[SegmentIndex]> {
+        if self.segments.is_empty() {
+            anyhow::bail!("Data file index is empty");
+        }
+        let i = self
+            .segments
+            .iter()
+            .rposition(|s| s.height <= height)
+            .unwrap_or(0);
+        Ok(&self.segments[i..])
+    }
+}
+
+/// Writes a warp data file
+///
+/// The header is only complete when the file is finished
+pub struct DataFileWriter<W: Write + Seek> {
+    w: W,
+    header: DataFileHeader,
+    segment: Vec<u8>,
+    segment_len: usize,
+    segment_height: u32,
+    offset: u64,
+    index: Vec<SegmentIndex>,
+    height: Option<u32>,
+}
+
+impl<W: Write + Seek> DataFileWriter<W> {
+    pub fn new(mut w: W, network: Network, spam_threshold: u32) -> Result<Self> {
+        let empty_frontier = TreeFrontier {
+            pos: 0,
+            prev: [[0u8; 32]; DEPTH + 1],
+            leaf: [0u8; 32],
+            ommers: [[0u8; 32]; DEPTH],
+        };
+        let header = DataFileHeader {
+            network,
+            start_height: 0,
+            end_height: 0,
+            spam_threshold,
+            sapling: empty_frontier.clone(),
+            orchard: empty_frontier,
+        };
+        // placeholder until finish
+        w.write_all(&[0u8; HEADER_LEN])?;
+        Ok(DataFileWriter {
+            w,
+            header,
+            segment: vec![],
+            segment_len: 0,
+            segment_height: 0,
+            offset: HEADER_LEN as u64,
+            index: vec![],
+            height: None,
+        })
+    }
+
+    pub fn write_block(&mut self, block: &CompactBlock) -> Result<()> {
+        let height = block.height as u32;
+        match self.height {
+            Some(prev_height) if height != prev_height + 1 => {
+                anyhow::bail!("Block {height} does not follow block {prev_height}")
+            }
+            None => self.header.start_height = height,
+            _ => (),
+        }
+        self.height = Some(height);
+        if self.segment_len == 0 {
+            self.segment_height = height;
+        }
+        let data = block.encode_to_vec();
+        self.segment.write_u32::<LE>(data.len() as u32)?;
+        self.segment.write_all(&data)?;
+        self.segment_len += 1;
+        if self.segment_len == SEGMENT_LEN {
+            self.flush_segment()?;
+        }
+        Ok(())
+    }
+
+    fn flush_segment(&mut self) -> Result<()> {
+        if self.segment_len == 0 {
+            return Ok(());
+        }
+        let mut data = vec![];
+        data.write_u32::<LE>(self.segment_len as u32)?;
+        data.append(&mut self.segment);
+        let cks = checksum(&data);
+        self.w.write_all(&data)?;
+        self.w.write_all(&cks)?;
+        self.index.push(SegmentIndex {
+            height: self.segment_height,
+            offset: self.offset,
+            checksum: cks,
+        });
+        self.offset += data.len() as u64 + 32;
+        self.segment_len = 0;
+        Ok(())
+    }
+
+    /// Write the index and the header with the final state of the trees
+    pub fn finish(
+        mut self,
+        sapling: TreeFrontier,
+        orchard: TreeFrontier,
+    ) -> Result<DataFileHeader> {
+        let end_height = self.height.ok_or(anyhow!("No blocks in data file"))?;
+        self.flush_segment()?;
+        self.w.write_u32::<LE>(0)?;
+        let index_offset = self.offset + 4;
+        let index = DataFileIndex {
+            segments: std::mem::take(&mut self.index),
+        };
+        index.write(&mut self.w)?;
+        self.w.write_u64::<LE>(index_offset)?;
+        self.w.write_all(MAGIC)?;
+
+        self.header.end_height = end_height;
+        self.header.sapling = sapling;
+        self.header.orchard = orchard;
+        self.w.seek(SeekFrom::Start(0))?;
+        self.header.write(&mut self.w)?;
+        self.w.seek(SeekFrom::End(0))?;
+        self.w.flush()?;
+        Ok(self.header)
+    }
+}
+
+/// Reads the blocks of a warp data file sequentially
+///
+/// Every segment is checked before its blocks are returned, and the
+/// file must end with the block at the end height of the header.
+/// When the index was read, the segments must also match it
+pub struct DataFileReader<R: Read> {
+    r: R,
+    header: DataFileHeader,
+    /// Index of the segments that are not read yet, if known
+    index: VecDeque<SegmentIndex>,
+    blocks: VecDeque<CompactBlock>,
+    height: Option<u32>,
+    done: bool,
+}
+
+impl<R: Read> DataFileReader<R> {
+    pub fn open(mut r: R) -> Result<Self> {
+        let header = DataFileHeader::read(&mut r)?;
+        Ok(Self::with_header(header, r))
+    }
+
+    /// Reader positioned at the start of a segment
+    pub fn with_header(header: DataFileHeader, r: R) -> Self {
+        Self::with_index(header, &[], r)
+    }
+
+    /// Reader positioned at the start of the first of `segments`,
+    /// which are checked against the index as they are read
+    pub fn with_index(header: DataFileHeader, segments: &[SegmentIndex], r: R) -> Self {
+        DataFileReader {
+            r,
+            header,
+            index: segments.iter().cloned().collect(),
+            blocks: VecDeque::new(),
+            height: None,
+            done: false,
+        }
+    }
+
+    /// Reader past the end of the file that returns no blocks
+    pub fn at_end(header: DataFileHeader, r: R) -> Self {
+        let height = Some(header.end_height);
+        DataFileReader {
+            r,
+            header,
+            index: VecDeque::new(),
+            blocks: VecDeque::new(),
+            height,
+            done: true,
+        }
+    }
+
+    pub fn header(&self) -> &DataFileHeader {
+        &self.header
+    }
+
+    fn read_segment(&mut self) -> Result<()> {
+        let height = self
+            .height
+            .map(|h| h + 1)
+            .unwrap_or(self.header.start_height);
+        let mut state = checksum_params().to_state();
+        let count = self.r.read_u32::<LE>().map_err(truncated)?;
+        state.update(&count.to_le_bytes());
+        if count as usize > SEGMENT_LEN {
+            anyhow::bail!("Corrupted data file segment at height {height}: {count} blocks");
+        }
+        if count == 0 {
+            self.done = true;
+            let end_height = self.header.end_height;
+            if self.height != Some(end_height) {
+                anyhow::bail!("Data file does not end at height {end_height}");
+            }
+            return Ok(());
+        }
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let len = self.r.read_u32::<LE>().map_err(truncated)?;
+            if len > MAX_BLOCK_LEN {
+                anyhow::bail!(
+                    "Corrupted data file segment at height {height}: block of {len} bytes"
+                );
+            }
+            let mut buf = vec![0; len as usize];
+            self.r.read_exact(&mut buf).map_err(truncated)?;
+            state.update(&len.to_le_bytes());
+            state.update(&buf);
+            blocks.push(buf);
+        }
+        let mut cks = [0u8; 32];
+        self.r.read_exact(&mut cks).map_err(truncated)?;
+        if state.finalize().as_bytes() != cks {
+            anyhow::bail!("Corrupted data file segment at height {height}");
+        }
+        let blocks = blocks
+            .iter()
+            .map(|buf| CompactBlock::decode(&**buf))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(segment) = self.index.pop_front() {
+            if segment.checksum != cks || segment.height as u64 != blocks[0].height {
+                anyhow::bail!("Data file segment at height {height} does not match the index");
+            }
+        }
+        // the blocks follow each other within the range of the header
+        let range = self.header.start_height as u64..=self.header.end_height as u64;
+        let mut prev = self.height.map(u64::from);
+        for cb in blocks.iter() {
+            if !range.contains(&cb.height) || prev.is_some_and(|p| cb.height != p + 1) {
+                anyhow::bail!(
+                    "Data file segment at height {height} has block {} out of order",
+                    cb.height
+                );
+            }
+            prev = Some(cb.height);
+        }
+        for cb in blocks {
+            self.height = Some(cb.height as u32);
+            self.blocks.push_back(cb);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> DataFileReader<R> {
+    /// Open the data file at the segment that contains `height`
+    ///
+    /// The blocks before `height` in that segment are returned too
+    pub fn open_at(mut r: R, height: u32) -> Result<Self> {
+        let header = DataFileHeader::read(&mut r)?;
+        if height > header.end_height {
+            return Ok(Self::at_end(header, r));
+        }
+        let index = DataFileIndex::load(&mut r)?;
+        let segments = index.segments_from(height)?;
+        r.seek(SeekFrom::Start(segments[0].offset))?;
+        Ok(Self::with_index(header, segments, r))
+    }
+}
+
+impl<R: Read + Send> BlockSource for DataFileReader<R> {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        if self.blocks.is_empty() && !self.done {
+            self.read_segment()?;
+        }
+        Ok(self.blocks.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warp::hasher::{OrchardHasher, SaplingHasher};
+    use crate::warp::testing::block_hash;
+    use std::io::Cursor;
+
+    const START: u32 = 1_000_000;
+    const COUNT: u32 = 2_500;
+
+    fn blocks() -> Vec<CompactBlock> {
+        (START..START + COUNT)
+            .map(|height| CompactBlock {
+                height: height as u64,
+                hash: block_hash(height),
+                prev_hash: block_hash(height - 1),
+                ..CompactBlock::default()
+            })
+            .collect()
+    }
+
+    /// A data file of [COUNT] blocks in 3 segments
+    fn data_file() -> Vec<u8> {
+        let sapling = MerkleTree::empty(SaplingHasher::default());
+        let orchard = MerkleTree::empty(OrchardHasher::default());
+        let mut data = vec![];
+        let mut w = DataFileWriter::new(Cursor::new(&mut data), Network::MainNetwork, 50).unwrap();
+        for b in blocks() {
+            w.write_block(&b).unwrap();
+        }
+        w.finish(
+            TreeFrontier::from_tree(&sapling).unwrap(),
+            TreeFrontier::from_tree(&orchard).unwrap(),
+        )
+        .unwrap();
+        data
+    }
+
+    fn read_all<R: Read + Send>(mut reader: DataFileReader<R>) -> Result<Vec<CompactBlock>> {
+        let mut blocks = vec![];
+        while let Some(b) = reader.next_block()? {
+            blocks.push(b);
+        }
+        Ok(blocks)
+    }
+
+    fn index(data: &[u8]) -> DataFileIndex {
+        DataFileIndex::load(Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = data_file();
+        let reader = DataFileReader::open(&*data).unwrap();
+        let header = reader.header().clone();
+        assert_eq!(header.start_height, START);
+        assert_eq!(header.end_height, START + COUNT - 1);
+        assert_eq!(header.spam_threshold, 50);
+        assert_eq!(read_all(reader).unwrap(), blocks());
+
+        let index = index(&data);
+        assert_eq!(index.segments.len(), 3);
+        assert_eq!(index.segments[2].height, START + 2 * SEGMENT_LEN as u32);
+        let reader = DataFileReader::open_at(Cursor::new(&data), START + 1500).unwrap();
+        assert_eq!(read_all(reader).unwrap(), blocks()[1000..]);
+        let reader = DataFileReader::open_at(Cursor::new(&data), START + COUNT).unwrap();
+        assert!(read_all(reader).unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupted_segment() {
+        let mut data = data_file();
+        let offset = index(&data).segments[1].offset as usize;
+        // in the first block of the second segment
+        data[offset + 10] ^= 1;
+        let error = read_all(DataFileReader::open(&*data).unwrap()).unwrap_err();
+        assert!(error.to_string().contains("Corrupted data file segment"));
+
+        let mut data = data_file();
+        data[HEADER_LEN - 40] ^= 1;
+        let error = DataFileReader::open(&*data).err().unwrap();
+        assert_eq!(error.to_string(), "Corrupted data file header");
+    }
+
+    #[test]
+    fn truncated_file() {
+        let data = data_file();
+        let offset = index(&data).segments[2].offset as usize;
+        let error = read_all(DataFileReader::open(&data[..offset + 100]).unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "Data file is truncated");
+        // without the end marker
+        let error = read_all(DataFileReader::open(&data[..offset]).unwrap()).unwrap_err();
+        assert_eq!(error.to_string(), "Data file is truncated");
+        let error = DataFileReader::open(&data[..HEADER_LEN - 1]).err().unwrap();
+        assert_eq!(error.to_string(), "Data file is truncated");
+    }
+
+    #[test]
+    fn index_mismatch() {
+        let data = data_file();
+        let mut index = index(&data);
+        let open = |index: &DataFileIndex| {
+            let header = DataFileHeader::read(&*data).unwrap();
+            let mut r = Cursor::new(&data);
+            r.seek(SeekFrom::Start(index.segments[1].offset)).unwrap();
+            read_all(DataFileReader::with_index(header, &index.segments[1..], r))
+        };
+        assert!(open(&index).is_ok());
+        index.segments[2].checksum[0] ^= 1;
+        assert!(open(&index).is_err());
+        index.segments[2].checksum[0] ^= 1;
+        index.segments[2].height += 1;
+        assert!(open(&index).is_err());
+
+        let mut data = data_file();
+        let len = data.len();
+        data[len - TRAILER_LEN - 40] ^= 1;
+        let error = DataFileIndex::load(Cursor::new(&data)).unwrap_err();
+        assert_eq!(error.to_string(), "Corrupted data file index");
+    }
+
+    #[test]
+    fn block_heights_are_checked() {
+        // without the second segment
+        let data = data_file();
+        let index = index(&data);
+        let (s1, s2) = (index.segments[1].offset, index.segments[2].offset);
+        let data = [&data[..s1 as usize], &data[s2 as usize..]].concat();
+        let error = read_all(DataFileReader::open(&*data).unwrap()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Data file segment at height {} has block {} out of order",
+                START + 1000,
+                START + 2000
+            )
+        );
+
+        // the first block is before the start of the header
+        let mut data = data_file();
+        let mut header = DataFileHeader::read(&*data).unwrap();
+        header.start_height += 1;
+        header.write(&mut data[..HEADER_LEN]).unwrap();
+        let error = read_all(DataFileReader::open(&*data).unwrap()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Data file segment at height {} has block {START} out of order",
+                START + 1
+            )
+        );
+    }
+
+    #[test]
+    fn header_mismatch() {
+        let mut data = data_file();
+        let mut header = DataFileHeader::read(&*data).unwrap();
+        header.end_height += 1;
+        header.write(&mut data[..HEADER_LEN]).unwrap();
+        let error = read_all(DataFileReader::open(&*data).unwrap()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Data file does not end at height {}", header.end_height)
+        );
+
+        header.network = Network::TestNetwork;
+        assert!(header.check_network(&Network::MainNetwork).is_err());
+
+        // the trees at the end of the file
+        let mut tree = MerkleTree::empty(SaplingHasher::default());
+        let frontier = TreeFrontier::from_tree(&tree).unwrap();
+        frontier.check(&tree).unwrap();
+        tree.add_nodes(0, 0, &[([3u8; 32], false)]);
+        assert!(frontier.check(&tree).is_err());
+        let frontier = TreeFrontier::from_tree(&tree).unwrap();
+        frontier.check(&tree).unwrap();
+
+        // after a bridge of lightwalletd, only the position and prev are known
+        tree.leaf = None;
+        assert!(TreeFrontier::from_tree(&tree).is_err());
+        frontier.check(&tree).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+use super::hasher::{check_nodes, OrchardHasher, SaplingHasher};
+use super::{Hash, Hasher, MerkleTree, DEPTH};
+use anyhow::Result;
+use incrementalmerkletree::bridgetree::{Frontier, Leaf};
+use orchard::tree::MerkleHashOrchard;
+use std::io::{Read, Write};
+use zcash_encoding::{Optional, Vector};
+use zcash_primitives::merkle_tree::incremental::{read_frontier_v1, write_frontier_v1};
+use zcash_primitives::merkle_tree::{CommitmentTree, HashSer};
+use zcash_primitives::sapling::Node;
+
+fn node_bytes<N: HashSer>(node: &N) -> Hash {
+    let mut bytes = [0u8; 32];
+    node.write(&mut bytes[..]).unwrap();
+    bytes
+}
+
+impl<H: Hasher<D = Hash>> MerkleTree<H> {
+    /// Build the tree that has the given frontier
+    ///
+    /// `prev[depth]` is the left node at `depth` that waits for its sibling.
+    /// A complete pair of leaves is combined with the ommers up to the first
+    /// depth where it is a left node
+    pub fn from_frontier<N: HashSer>(frontier: &Frontier<N, { DEPTH as u8 }>, h: H) -> Self {
+        let mut tree = MerkleTree::empty(h);
+        let Some(frontier) = frontier.value() else {
+            return tree;
+        };
+        let position = usize::from(frontier.position());
+        let mut carry = match frontier.leaf() {
+            Leaf::Left(a) => {
+                tree.prev[0] = node_bytes(a);
+                tree.leaf = Some(tree.prev[0]);
+                None
+            }
+            Leaf::Right(a, b) => {
+                let leaf = node_bytes(b);
+                tree.ommers[0] = node_bytes(a);
+                tree.leaf = Some(leaf);
+                Some(tree.h.combine(0, &tree.ommers[0], &leaf, false))
+            }
+        };
+        let mut ommers = frontier.ommers().iter();
+        for depth in 1..DEPTH {
+            if position & (1 << depth) != 0 {
+                let ommer = node_bytes(ommers.next().unwrap());
+                tree.ommers[depth] = ommer;
+                match carry {
+                    Some(c) => carry = Some(tree.h.combine(depth as u8, &ommer, &c, false)),
+                    None => tree.prev[depth] = ommer,
+                }
+            } else if let Some(c) = carry.take() {
+                tree.prev[depth] = c;
+            }
+        }
+        tree.pos = position + 1;
+        tree
+    }
+}
+
+/// Frontier of a non empty tree
+pub(super) struct FrontierNodes {
+    pub position: usize,
+    /// The last leaf if it is a left leaf, its left sibling otherwise
+    pub left: Hash,
+    /// The last leaf if it is a right leaf
+    pub right: Option<Hash>,
+    /// Ommers by depth, at the depths where `position` has a 1 bit
+    pub ommers: Vec<(usize, Hash)>,
+}
+
+impl<H: Hasher<D = Hash>> MerkleTree<H> {
+    /// The ommers below the first 0 bit of the position come from `ommers`,
+    /// the others are still waiting for their sibling in `prev`
+    ///
+    /// Fail if they are unknown because the tree was extended by a bridge
+    /// without them
+    pub(super) fn frontier_nodes(&self) -> Result<Option<FrontierNodes>> {
+        if self.pos == 0 {
+            return Ok(None);
+        }
+        let Some(leaf) = self.leaf else {
+            anyhow::bail!("The frontier of the tree of size {} is unknown", self.pos);
+        };
+        let position = self.pos - 1;
+        let combined = super::tree::combined_ommers(self.pos);
+        let node = |depth: usize| {
+            if depth < combined {
+                self.ommers[depth]
+            } else {
+                self.prev[depth]
+            }
+        };
+        let (left, right) = if position & 1 == 0 {
+            (self.prev[0], None)
+        } else {
+            (node(0), Some(leaf))
+        };
+        let ommers = (1..DEPTH)
+            .filter(|depth| position & (1 << depth) != 0)
+            .map(|depth| (depth, node(depth)))
+            .collect();
+        Ok(Some(FrontierNodes {
+            position,
+            left,
+            right,
+            ommers,
+        }))
+    }
+
+    /// Frontier of the tree
+    pub fn to_frontier<N: HashSer + Clone>(&self) -> Result<Frontier<N, { DEPTH as u8 }>> {
+        let Some(nodes) = self.frontier_nodes()? else {
+            return Ok(Frontier::empty());
+        };
+        let leaf = match nodes.right {
+            None => Leaf::Left(read_node(&nodes.left)?),
+            Some(right) => Leaf::Right(read_node(&nodes.left)?, read_node(&right)?),
+        };
+        let ommers = nodes
+            .ommers
+            .iter()
+            .map(|(_, o)| read_node(o))
+            .collect::<Result<Vec<_>>>()?;
+        Frontier::from_parts(nodes.position.into(), leaf, ommers)
+            .map_err(|e| anyhow::anyhow!("Invalid frontier: {e:?}"))
+    }
+
+    pub fn from_commitment_tree<N>(tree: &CommitmentTree<N>, h: H) -> Self
+    where
+        N: incrementalmerkletree::Hashable + HashSer + Clone,
+    {
+        Self::from_frontier(&tree.to_frontier(), h)
+    }
+
+    /// Commitment tree in the format of zcashd
+    pub fn to_commitment_tree<N: HashSer + Clone>(&self) -> Result<CommitmentTree<N>> {
+        Ok(CommitmentTree::from_frontier(&self.to_frontier()?))
+    }
+
+    /// Read a frontier in the incrementalmerkletree v1 encoding
+    pub fn read_frontier<N: HashSer + Clone, R: Read>(r: R, h: H) -> Result<Self> {
+        let frontier = read_frontier_v1::<N, _>(r)?;
+        let tree = Self::from_frontier(&frontier, h);
+        check_nodes(&tree.h, &tree.prev)?;
+        check_nodes(&tree.h, tree.leaf.as_slice())?;
+        check_nodes(&tree.h, &tree.ommers)?;
+        Ok(tree)
+    }
+
+    /// Write the frontier in the incrementalmerkletree v1 encoding
+    pub fn write_frontier<N: HashSer + Clone, W: Write>(&self, w: W) -> Result<()> {
+        write_frontier_v1(w, &self.to_frontier::<N>()?)?;
+        Ok(())
+    }
+}
+
+fn read_node<N: HashSer>(node: &Hash) -> Result<N> {
+    Ok(N::read(&node[..])?)
+}
+
+/// Sapling tree from the `sapling_tree` of a lightwalletd `TreeState`
+pub fn sapling_tree_from_hex(tree: &str) -> Result<MerkleTree<SaplingHasher>> {
+    if tree.is_empty() {
+        return Ok(MerkleTree::empty(SaplingHasher::default()));
+    }
+    let tree = CommitmentTree::<Node>::read(&*hex::decode(tree)?)?;
+    Ok(MerkleTree::from_commitment_tree(
+        &tree,
+        SaplingHasher::default(),
+    ))
+}
+
+/// Orchard tree from the `orchard_tree` of a lightwalletd `TreeState`
+pub fn orchard_tree_from_hex(tree: &str) -> Result<MerkleTree<OrchardHasher>> {
+    if tree.is_empty() {
+        return Ok(MerkleTree::empty(OrchardHasher::default()));
+    }
+    let tree = CommitmentTree::<MerkleHashOrchard>::read(&*hex::decode(tree)?)?;
+    Ok(MerkleTree::from_commitment_tree(
+        &tree,
+        OrchardHasher::default(),
+    ))
+}
+
+/// Tree in the format of `TreeState.sapling_tree` and `TreeState.orchard_tree`
+///
+/// Like zcashd, the empty parents at the top are not written
+pub fn tree_to_hex<H: Hasher<D = Hash>>(tree: &MerkleTree<H>) -> Result<String> {
+    let mut left = None;
+    let mut right = None;
+    let mut parents = vec![];
+    if let Some(nodes) = tree.frontier_nodes()? {
+        left = Some(nodes.left);
+        right = nodes.right;
+        for (depth, ommer) in nodes.ommers {
+            parents.resize(depth - 1, None);
+            parents.push(Some(ommer));
+        }
+    }
+    let mut data = vec![];
+    Optional::write(&mut data, left, |w, n| w.write_all(&n))?;
+    Optional::write(&mut data, right, |w, n| w.write_all(&n))?;
+    Vector::write(&mut data, &parents, |w, p| {
+        Optional::write(w, *p, |w, n| w.write_all(&n))
+    })?;
+    Ok(hex::encode(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warp::testing::leaf;
+    use crate::warp::{empty_roots, Bridge};
+    use std::fmt::Debug;
+
+    /// Append the leaves up to `size` to the reference tree
+    fn grow<N>(tree: &mut CommitmentTree<N>, size: usize)
+    where
+        N: incrementalmerkletree::Hashable + HashSer + Copy,
+    {
+        for i in tree.size()..size {
+            tree.append(read_node(&leaf(i)).unwrap()).unwrap();
+        }
+    }
+
+    fn hex_of<N>(tree: &CommitmentTree<N>) -> String
+    where
+        N: incrementalmerkletree::Hashable + HashSer + Copy,
+    {
+        let mut data = vec![];
+        tree.write(&mut data).unwrap();
+        hex::encode(data)
+    }
+
+    /// The tree has the frontier and the root of the reference tree
+    fn check<H, N>(tree: &MerkleTree<H>, expected: &CommitmentTree<N>)
+    where
+        H: Hasher<D = Hash>,
+        N: incrementalmerkletree::Hashable + HashSer + Copy + PartialEq + Debug,
+    {
+        let size = expected.size();
+        let root = tree.edge(&empty_roots(&tree.h))[DEPTH - 1];
+        assert_eq!(root, node_bytes(&expected.root()), "root of size {size}");
+        let frontier = tree.to_frontier::<N>().unwrap();
+        assert_eq!(frontier, expected.to_frontier(), "frontier of size {size}");
+        assert_eq!(tree.to_commitment_tree::<N>().unwrap().size(), size);
+        assert_eq!(tree_to_hex(tree).unwrap(), hex_of(expected));
+        let mut data = vec![];
+        tree.write_frontier::<N, _>(&mut data).unwrap();
+        let tree2 = MerkleTree::read_frontier::<N, _>(&*data, H::default()).unwrap();
+        assert_eq!(tree2.to_frontier::<N>().unwrap(), frontier);
+    }
+
+    fn round_trip<H, N>()
+    where
+        H: Hasher<D = Hash>,
+        N: incrementalmerkletree::Hashable + HashSer + Copy + PartialEq + Debug,
+    {
+        let mut ct = CommitmentTree::<N>::empty();
+        for size in 0..34 {
+            grow(&mut ct, size);
+            let tree = MerkleTree::from_commitment_tree(&ct, H::default());
+            assert_eq!(tree.pos, size);
+            check(&tree, &ct);
+        }
+    }
+
+    /// Trees grown by adding nodes, by adding bridges and read back
+    /// from their serialization
+    fn add_nodes_and_bridges<H, N>()
+    where
+        H: Hasher<D = Hash>,
+        N: incrementalmerkletree::Hashable + HashSer + Copy + PartialEq + Debug,
+    {
+        let mut tree = MerkleTree::empty(H::default());
+        let mut bridged = MerkleTree::empty(H::default());
+        let mut pending: Option<Bridge<H>> = None;
+        let mut ct = CommitmentTree::<N>::empty();
+        let mut size = 0;
+        let lens = [1, 2, 3, 1, 1, 4, 5, 2, 7, 8, 1, 16, 3, 6];
+        for (i, &len) in lens.iter().cycle().take(24).enumerate() {
+            let nodes: Vec<_> = (size..size + len).map(|i| (leaf(i), false)).collect();
+            let bridge = tree.add_nodes(0, 0, &nodes);
+            size += len;
+            grow(&mut ct, size);
+            check(&tree, &ct);
+
+            let mut data = vec![];
+            tree.write(&mut data).unwrap();
+            let tree2 = MerkleTree::read(&*data, H::default()).unwrap();
+            check(&tree2, &ct);
+
+            // some bridges are merged, like the bridges of the transactions of a block
+            let mut data = vec![];
+            bridge.write(&mut data, &tree.h).unwrap();
+            let bridge = Bridge::read(&*data, &tree.h).unwrap();
+            match pending.take() {
+                Some(mut b) => {
+                    b.merge(&bridge, &tree.h);
+                    bridged.add_bridge(&b);
+                    check(&bridged, &ct);
+                }
+                None if i % 3 == 0 => pending = Some(bridge),
+                None => {
+                    bridged.add_bridge(&bridge);
+                    check(&bridged, &ct);
+                }
+            }
+        }
+    }
+
+    /// The bridges of lightwalletd do not have the last leaf and the ommers.
+    /// The frontier is unknown after them, until more leaves are added
+    fn bridges_without_frontier<H, N>()
+    where
+        H: Hasher<D = Hash>,
+        N: incrementalmerkletree::Hashable + HashSer + Copy + PartialEq + Debug,
+    {
+        let mut tree = MerkleTree::empty(H::default());
+        let mut bridged = MerkleTree::empty(H::default());
+        let mut ct = CommitmentTree::<N>::empty();
+        let mut size = 0;
+        for len in [3, 1, 6, 2, 7] {
+            let nodes: Vec<_> = (size..size + len).map(|i| (leaf(i), false)).collect();
+            let mut bridge = tree.add_nodes(0, 0, &nodes);
+            size += len;
+            grow(&mut ct, size);
+            bridge.leaf = None;
+            let mut data = vec![];
+            bridge.write(&mut data, &tree.h).unwrap();
+            let bridge = Bridge::read(&*data, &tree.h).unwrap();
+            assert!(bridge.leaf.is_none());
+            bridged.add_bridge(&bridge);
+            assert_eq!(bridged.prev, tree.prev);
+            assert!(bridged.to_frontier::<N>().is_err());
+            assert!(tree_to_hex(&bridged).is_err());
+
+            let mut data = vec![];
+            bridged.write(&mut data).unwrap();
+            let tree2 = MerkleTree::read(&*data, H::default()).unwrap();
+            assert!(tree2.leaf.is_none());
+
+            let nodes = [(leaf(size), false)];
+            tree.add_nodes(0, 0, &nodes);
+            bridged.add_nodes(0, 0, &nodes);
+            size += 1;
+            grow(&mut ct, size);
+            check(&bridged, &ct);
+        }
+    }
+
+    #[test]
+    fn sapling_commitment_tree_round_trip() {
+        round_trip::<SaplingHasher, Node>();
+    }
+
+    #[test]
+    fn orchard_commitment_tree_round_trip() {
+        round_trip::<OrchardHasher, MerkleHashOrchard>();
+    }
+
+    #[test]
+    fn sapling_frontier_after_nodes_and_bridges() {
+        add_nodes_and_bridges::<SaplingHasher, Node>();
+    }
+
+    #[test]
+    fn orchard_frontier_after_nodes_and_bridges() {
+        add_nodes_and_bridges::<OrchardHasher, MerkleHashOrchard>();
+    }
+
+    #[test]
+    fn frontier_after_bridges_without_frontier() {
+        bridges_without_frontier::<SaplingHasher, Node>();
+        bridges_without_frontier::<OrchardHasher, MerkleHashOrchard>();
+    }
+}
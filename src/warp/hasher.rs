@@ -1,4 +1,8 @@
 use super::{Hash, Hasher};
+use anyhow::Result;
+use incrementalmerkletree::{Altitude, Hashable};
+use orchard::tree::MerkleHashOrchard;
+use rayon::prelude::*;
 
 #[derive(Clone, Debug)]
 pub struct SaplingHasher {
@@ -21,6 +25,7 @@ impl Default for SaplingHasher {
 }
 
 impl Hasher for SaplingHasher {
+    const ID: u8 = 0;
     type D = Hash;
     fn empty(&self) -> Hash {
         self.empty
@@ -30,6 +35,10 @@ impl Hasher for SaplingHasher {
         *d == self.empty
     }
 
+    fn is_valid(&self, d: &Hash) -> bool {
+        jubjub::Base::from_bytes(d).is_some().into()
+    }
+
     fn combine(&self, depth: u8, l: &Hash, r: &Hash, _check: bool) -> Hash {
         // println!("> {} {} {}", depth, hex::encode(l), hex::encode(r));
         crate::sapling::sapling_hash(depth, l, r)
@@ -39,3 +48,66 @@ impl Hasher for SaplingHasher {
         crate::sapling::sapling_parallel_hash(depth, layer, pairs)
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct OrchardHasher {
+    empty: Hash,
+}
+
+impl OrchardHasher {
+    pub fn new() -> Self {
+        let empty = MerkleHashOrchard::empty_leaf().to_bytes();
+
+        Self { empty }
+    }
+}
+
+impl Default for OrchardHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for OrchardHasher {
+    const ID: u8 = 1;
+    type D = Hash;
+    fn empty(&self) -> Hash {
+        self.empty
+    }
+
+    fn is_empty(&self, d: &Hash) -> bool {
+        *d == self.empty
+    }
+
+    fn is_valid(&self, d: &Hash) -> bool {
+        MerkleHashOrchard::from_bytes(d).is_some().into()
+    }
+
+    fn combine(&self, depth: u8, l: &Hash, r: &Hash, _check: bool) -> Hash {
+        orchard_hash(depth, l, r)
+    }
+
+    fn parallel_combine(&self, depth: u8, layer: &[Hash], pairs: usize) -> Vec<Hash> {
+        (0..pairs)
+            .into_par_iter()
+            .map(|i| orchard_hash(depth, &layer[2 * i], &layer[2 * i + 1]))
+            .collect()
+    }
+}
+
+/// Fail if one of `nodes` is not canonical, see [Hasher::is_valid]
+pub fn check_nodes<H: Hasher>(h: &H, nodes: &[H::D]) -> Result<()> {
+    if let Some(n) = nodes.iter().find(|n| !h.is_valid(n)) {
+        anyhow::bail!("Invalid node {n:?}");
+    }
+    Ok(())
+}
+
+/// Sinsemilla MerkleCRH over Pallas
+///
+/// The nodes must be canonical, see [Hasher::is_valid]
+fn orchard_hash(depth: u8, l: &Hash, r: &Hash) -> Hash {
+    let l = MerkleHashOrchard::from_bytes(l).unwrap();
+    let r = MerkleHashOrchard::from_bytes(r).unwrap();
+    MerkleHashOrchard::combine(Altitude::from(depth), &l, &r).to_bytes()
+}
@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Result};
+use bip39::{Language, Mnemonic, Seed};
+use zcash_client_backend::encoding::decode_extended_full_viewing_key;
+use zcash_client_backend::keys::{UnifiedFullViewingKey, UnifiedSpendingKey};
+use zcash_primitives::consensus::{Network, Parameters};
+use zcash_primitives::legacy::keys::{AccountPubKey, IncomingViewingKey};
+use zcash_primitives::legacy::TransparentAddress;
+use zcash_primitives::zip32::{AccountId, DiversifiableFullViewingKey, Scope};
+
+/// Number of consecutive transparent addresses without transactions after
+/// which the scan of a chain of addresses stops (BIP-44 gap limit)
+pub const TRANSPARENT_GAP_LIMIT: u32 = 20;
+
+/// Viewing keys of an account
+#[derive(Clone, Debug)]
+pub struct ViewingKeys {
+    pub sapling: Option<DiversifiableFullViewingKey>,
+    pub orchard: Option<orchard::keys::FullViewingKey>,
+    /// BIP-44 account public key of the transparent addresses
+    pub transparent: Option<AccountPubKey>,
+}
+
+impl ViewingKeys {
+    /// Decode either a sapling extended full viewing key (`zxviews...`)
+    /// or a unified full viewing key (`uview...`)
+    pub fn decode(network: &Network, key: &str) -> Result<Self> {
+        if key.starts_with(network.hrp_sapling_extended_full_viewing_key()) {
+            let efvk = decode_extended_full_viewing_key(
+                network.hrp_sapling_extended_full_viewing_key(),
+                key,
+            )
+            .map_err(|e| anyhow!("Invalid sapling viewing key: {e}"))?;
+            return Ok(ViewingKeys {
+                sapling: Some(efvk.to_diversifiable_full_viewing_key()),
+                orchard: None,
+                transparent: None,
+            });
+        }
+        let ufvk = UnifiedFullViewingKey::decode(network, key)
+            .map_err(|e| anyhow!("Invalid unified viewing key: {e}"))?;
+        Ok(Self::from_ufvk(&ufvk))
+    }
+
+    /// Derive the keys of the account `account_index` from a BIP-39 seed phrase
+    pub fn from_seed(network: &Network, phrase: &str, account_index: u32) -> Result<Self> {
+        let mnemonic = Mnemonic::from_phrase(phrase, Language::English)?;
+        let seed = Seed::new(&mnemonic, "");
+        let usk =
+            UnifiedSpendingKey::from_seed(network, seed.as_bytes(), AccountId::from(account_index))
+                .map_err(|e| anyhow!("Cannot derive the keys of account {account_index}: {e:?}"))?;
+        Ok(Self::from_ufvk(&usk.to_unified_full_viewing_key()))
+    }
+
+    fn from_ufvk(ufvk: &UnifiedFullViewingKey) -> Self {
+        ViewingKeys {
+            sapling: ufvk.sapling().cloned(),
+            orchard: ufvk.orchard().cloned(),
+            transparent: ufvk.transparent().cloned(),
+        }
+    }
+
+    /// Transparent address at the child `index` of the external
+    /// (receiving) or internal (change) chain
+    ///
+    /// None if there is no transparent key or if the index does not give
+    /// a valid key
+    pub fn transparent_address(&self, scope: Scope, index: u32) -> Option<TransparentAddress> {
+        let tpk = self.transparent.as_ref()?;
+        match scope {
+            Scope::External => tpk.derive_external_ivk().ok()?.derive_address(index).ok(),
+            Scope::Internal => tpk.derive_internal_ivk().ok()?.derive_address(index).ok(),
+        }
+    }
+}
+
+/// An account to scan
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub id: u32,
+    pub keys: ViewingKeys,
+}
+
+impl Account {
+    pub fn decode(network: &Network, id: u32, key: &str) -> Result<Self> {
+        Ok(Account {
+            id,
+            keys: ViewingKeys::decode(network, key)?,
+        })
+    }
+
+    /// Account `id` of the wallet with the seed `phrase`, at the BIP-44/ZIP-32
+    /// account index `id`
+    pub fn from_seed(network: &Network, id: u32, phrase: &str) -> Result<Self> {
+        Ok(Account {
+            id,
+            keys: ViewingKeys::from_seed(network, phrase, id)?,
+        })
+    }
+}
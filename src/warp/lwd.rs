@@ -0,0 +1,132 @@
+use super::Hash;
+use crate::lw_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::lw_rpc::{
+    BlockId, BlockRange, ChainSpec, CompactBlock, CompactTx, Exclude, RawTransaction,
+    TransparentAddressBlockFilter, TreeState, TxFilter,
+};
+use anyhow::Result;
+use tonic::transport::{Channel, ClientTlsConfig};
+use tonic::{Request, Streaming};
+
+pub async fn connect_lightwalletd(url: &str) -> Result<CompactTxStreamerClient<Channel>> {
+    let mut channel = Channel::from_shared(url.to_owned())?;
+    if url.starts_with("https") {
+        let tls = ClientTlsConfig::new();
+        channel = channel.tls_config(tls)?;
+    }
+    let client = CompactTxStreamerClient::connect(channel).await?;
+    Ok(client)
+}
+
+pub async fn get_latest_height(client: &mut CompactTxStreamerClient<Channel>) -> Result<u32> {
+    let rep = client
+        .get_latest_block(Request::new(ChainSpec {}))
+        .await?
+        .into_inner();
+    Ok(rep.height as u32)
+}
+
+/// Commitment trees after the block at `height`
+pub async fn get_tree_state(
+    client: &mut CompactTxStreamerClient<Channel>,
+    height: u32,
+) -> Result<TreeState> {
+    let rep = client
+        .get_tree_state(Request::new(BlockId {
+            height: height as u64,
+            hash: vec![],
+        }))
+        .await?
+        .into_inner();
+    Ok(rep)
+}
+
+/// Request the blocks from `start_height` to `end_height` (inclusive)
+///
+/// Transactions with more than `spam_threshold` inputs/outputs/actions are
+/// replaced by bridges by the server.
+pub async fn get_block_range(
+    client: &mut CompactTxStreamerClient<Channel>,
+    start_height: u32,
+    end_height: u32,
+    spam_threshold: u64,
+) -> Result<Streaming<CompactBlock>> {
+    let blocks = client
+        .get_block_range(Request::new(BlockRange {
+            start: Some(BlockId {
+                height: start_height as u64,
+                hash: vec![],
+            }),
+            end: Some(BlockId {
+                height: end_height as u64,
+                hash: vec![],
+            }),
+            spam_filter_threshold: spam_threshold,
+        }))
+        .await?
+        .into_inner();
+    Ok(blocks)
+}
+
+/// Full transaction with the given `txid`
+pub async fn get_transaction(
+    client: &mut CompactTxStreamerClient<Channel>,
+    txid: &Hash,
+) -> Result<RawTransaction> {
+    let rep = client
+        .get_transaction(Request::new(TxFilter {
+            block: None,
+            index: 0,
+            hash: txid.to_vec(),
+        }))
+        .await?
+        .into_inner();
+    Ok(rep)
+}
+
+/// Transactions that pay to or spend from the transparent `address`
+/// between `start_height` and `end_height` (inclusive)
+pub async fn get_transparent_txs(
+    client: &mut CompactTxStreamerClient<Channel>,
+    address: &str,
+    start_height: u32,
+    end_height: u32,
+) -> Result<Vec<RawTransaction>> {
+    let mut txs = client
+        .get_taddress_txids(Request::new(TransparentAddressBlockFilter {
+            address: address.to_owned(),
+            range: Some(BlockRange {
+                start: Some(BlockId {
+                    height: start_height as u64,
+                    hash: vec![],
+                }),
+                end: Some(BlockId {
+                    height: end_height as u64,
+                    hash: vec![],
+                }),
+                spam_filter_threshold: 0,
+            }),
+        }))
+        .await?
+        .into_inner();
+    let mut res = vec![];
+    while let Some(tx) = txs.message().await? {
+        res.push(tx);
+    }
+    Ok(res)
+}
+
+/// Compact transactions of the mempool
+pub async fn get_mempool_txs(
+    client: &mut CompactTxStreamerClient<Channel>,
+) -> Result<Vec<CompactTx>> {
+    let mut txs = client
+        .get_mempool_tx(Request::new(Exclude { txid: vec![] }))
+        .await?
+        .into_inner();
+    let mut res = vec![];
+    while let Some(tx) = txs.message().await? {
+        res.push(tx);
+    }
+    Ok(res)
+}
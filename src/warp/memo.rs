@@ -0,0 +1,354 @@
+use super::keys::Account;
+use super::note::{ReceivedNote, SentOutput};
+use super::scan::ScanResult;
+use super::source::TransactionSource;
+use super::Hash;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use zcash_client_backend::address::{RecipientAddress, UnifiedAddress};
+use zcash_note_encryption::{try_note_decryption, try_output_recovery_with_ovk};
+use zcash_primitives::consensus::{BlockHeight, BranchId, Network};
+use zcash_primitives::memo::MemoBytes;
+use zcash_primitives::sapling::note_encryption::{
+    try_sapling_note_decryption, try_sapling_output_recovery, PreparedIncomingViewingKey,
+};
+use zcash_primitives::transaction::Transaction;
+use zcash_primitives::zip32::Scope;
+
+/// Recover the memos of the notes of `result` that do not have one yet
+///
+/// The transactions that contain the notes are fetched from `source`, once
+/// each, and the outputs/actions of the notes are fully decrypted with the
+/// incoming viewing keys of their accounts
+pub async fn fetch_memos<S: TransactionSource>(
+    network: &Network,
+    accounts: &[Account],
+    source: &mut S,
+    result: &mut ScanResult,
+) -> Result<()> {
+    // the notes without memo, by transaction
+    let mut txs: BTreeMap<Hash, (Vec<&mut ReceivedNote>, Vec<&mut ReceivedNote>)> = BTreeMap::new();
+    let sapling_notes = result
+        .sapling
+        .notes
+        .iter_mut()
+        .map(|n| &mut n.note)
+        .chain(result.sapling.spent_notes.iter_mut().map(|n| &mut n.note));
+    for n in sapling_notes.filter(|n| n.memo.is_none()) {
+        txs.entry(n.txid).or_default().0.push(n);
+    }
+    let orchard_notes = result
+        .orchard
+        .notes
+        .iter_mut()
+        .map(|n| &mut n.note)
+        .chain(result.orchard.spent_notes.iter_mut().map(|n| &mut n.note));
+    for n in orchard_notes.filter(|n| n.memo.is_none()) {
+        txs.entry(n.txid).or_default().1.push(n);
+    }
+
+    for (txid, (sapling_notes, orchard_notes)) in txs {
+        if txid == [0u8; 32] {
+            anyhow::bail!("The txids of the notes are unknown");
+        }
+        let raw_tx = source.get_transaction(&txid).await?;
+        let height = BlockHeight::from_u32(raw_tx.height as u32);
+        let tx = Transaction::read(&*raw_tx.data, BranchId::for_height(network, height))?;
+        for n in sapling_notes {
+            let memo = decrypt_sapling_memo(network, accounts, &tx, n)?;
+            n.memo = Some(memo);
+        }
+        for n in orchard_notes {
+            let memo = decrypt_orchard_memo(accounts, &tx, n)?;
+            n.memo = Some(memo);
+        }
+    }
+    Ok(())
+}
+
+fn decrypt_sapling_memo(
+    network: &Network,
+    accounts: &[Account],
+    tx: &Transaction,
+    note: &ReceivedNote,
+) -> Result<MemoBytes> {
+    let fvk = accounts
+        .iter()
+        .find(|a| a.id == note.account)
+        .and_then(|a| a.keys.sapling.as_ref())
+        .ok_or(anyhow!("No sapling key for account {}", note.account))?;
+    let output = tx
+        .sapling_bundle()
+        .and_then(|b| b.shielded_outputs().get(note.output_index as usize))
+        .ok_or(anyhow!("No sapling output {}", note.output_index))?;
+    let ivk = PreparedIncomingViewingKey::new(&fvk.to_ivk(note.scope));
+    let (_, _, memo) =
+        try_sapling_note_decryption(network, BlockHeight::from_u32(note.height), &ivk, output)
+            .ok_or(anyhow!(
+                "Cannot decrypt the sapling output {} of {}",
+                note.output_index,
+                hex::encode(note.txid)
+            ))?;
+    Ok(memo)
+}
+
+fn decrypt_orchard_memo(
+    accounts: &[Account],
+    tx: &Transaction,
+    note: &ReceivedNote,
+) -> Result<MemoBytes> {
+    let fvk = accounts
+        .iter()
+        .find(|a| a.id == note.account)
+        .and_then(|a| a.keys.orchard.as_ref())
+        .ok_or(anyhow!("No orchard key for account {}", note.account))?;
+    let action = tx
+        .orchard_bundle()
+        .and_then(|b| b.actions().get(note.output_index as usize))
+        .ok_or(anyhow!("No orchard action {}", note.output_index))?;
+    let ivk =
+        orchard::keys::PreparedIncomingViewingKey::new(&fvk.to_ivk(orchard_scope(note.scope)));
+    let domain = orchard::note_encryption::OrchardDomain::for_action(action);
+    let (_, _, memo) = try_note_decryption(&domain, &ivk, action).ok_or(anyhow!(
+        "Cannot decrypt the orchard action {} of {}",
+        note.output_index,
+        hex::encode(note.txid)
+    ))?;
+    MemoBytes::from_bytes(&memo).map_err(|_| anyhow!("Invalid memo"))
+}
+
+/// List the outputs of the transactions that spent the notes of `result`
+///
+/// The transactions are fetched from `source` and their outputs/actions are
+/// recovered with the external and internal outgoing viewing keys of the
+/// accounts that spent from them. The outputs that were not encrypted with
+/// these keys are not recoverable and are skipped. The outputs to an address
+/// of the account that spent are change
+pub async fn fetch_sent_outputs<S: TransactionSource>(
+    network: &Network,
+    accounts: &[Account],
+    source: &mut S,
+    result: &mut ScanResult,
+) -> Result<()> {
+    // the accounts that spent in every transaction
+    let mut spends = BTreeSet::new();
+    for n in result
+        .sapling
+        .spent_notes
+        .iter()
+        .chain(result.orchard.spent_notes.iter())
+    {
+        spends.insert((n.spent_txid, n.note.account));
+    }
+
+    let mut txs: BTreeMap<Hash, Vec<u32>> = BTreeMap::new();
+    for (txid, account) in spends {
+        txs.entry(txid).or_default().push(account);
+    }
+    for (txid, spending_accounts) in txs {
+        if txid == [0u8; 32] {
+            anyhow::bail!("The txids of the spends are unknown");
+        }
+        let raw_tx = source.get_transaction(&txid).await?;
+        let height = raw_tx.height as u32;
+        let tx = Transaction::read(
+            &*raw_tx.data,
+            BranchId::for_height(network, BlockHeight::from_u32(height)),
+        )?;
+        for account in accounts
+            .iter()
+            .filter(|a| spending_accounts.contains(&a.id))
+        {
+            for scope in [Scope::External, Scope::Internal] {
+                let sent = |output_index: usize,
+                            address: String,
+                            value: u64,
+                            memo: MemoBytes,
+                            change: bool| {
+                    SentOutput {
+                        account: account.id,
+                        height,
+                        txid,
+                        output_index: output_index as u32,
+                        address,
+                        value,
+                        memo,
+                        change,
+                    }
+                };
+                if let (Some(fvk), Some(bundle)) =
+                    (account.keys.sapling.as_ref(), tx.sapling_bundle())
+                {
+                    let ovk = fvk.to_ovk(scope);
+                    for (i, output) in bundle.shielded_outputs().iter().enumerate() {
+                        if let Some((note, address, memo)) = try_sapling_output_recovery(
+                            network,
+                            BlockHeight::from_u32(height),
+                            &ovk,
+                            output,
+                        ) {
+                            let change = fvk.decrypt_diversifier(&address).is_some();
+                            result.sapling.sent_outputs.push(sent(
+                                i,
+                                RecipientAddress::Shielded(address).encode(network),
+                                note.value().inner(),
+                                memo,
+                                change,
+                            ));
+                        }
+                    }
+                }
+                if let (Some(fvk), Some(bundle)) =
+                    (account.keys.orchard.as_ref(), tx.orchard_bundle())
+                {
+                    let ovk = fvk.to_ovk(orchard_scope(scope));
+                    for (i, action) in bundle.actions().iter().enumerate() {
+                        let domain = orchard::note_encryption::OrchardDomain::for_action(action);
+                        if let Some((note, address, memo)) = try_output_recovery_with_ovk(
+                            &domain,
+                            &ovk,
+                            action,
+                            action.cv_net(),
+                            &action.encrypted_note().out_ciphertext,
+                        ) {
+                            let change = fvk.scope_for_address(&address).is_some();
+                            let address = UnifiedAddress::from_receivers(Some(address), None, None)
+                                .ok_or(anyhow!("Invalid orchard address"))?;
+                            let memo = MemoBytes::from_bytes(&memo)
+                                .map_err(|_| anyhow!("Invalid memo"))?;
+                            result.orchard.sent_outputs.push(sent(
+                                i,
+                                RecipientAddress::Unified(address).encode(network),
+                                note.value().inner(),
+                                memo,
+                                change,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn orchard_scope(scope: Scope) -> orchard::keys::Scope {
+    match scope {
+        Scope::External => orchard::keys::Scope::External,
+        Scope::Internal => orchard::keys::Scope::Internal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::scan::{scan_source, ScanHandle};
+    use super::super::source::{MemorySource, MemoryTransactionSource};
+    use super::super::state::SyncState;
+    use super::super::testing::{block_hash, sapling_tx, PHRASE, START_HEIGHT};
+    use super::*;
+    use crate::lw_rpc::{CompactBlock, CompactSaplingSpend, CompactTx};
+    use std::collections::HashMap;
+
+    fn block(height: u32, tx: CompactTx) -> CompactBlock {
+        CompactBlock {
+            height: height as u64,
+            hash: block_hash(height),
+            prev_hash: block_hash(height - 1),
+            vtx: vec![tx],
+            ..CompactBlock::default()
+        }
+    }
+
+    fn scan(accounts: &[Account], blocks: Vec<CompactBlock>) -> ScanResult {
+        scan_source(
+            &Network::MainNetwork,
+            accounts,
+            MemorySource::new(blocks),
+            None,
+            SyncState::new(),
+            None,
+            &ScanHandle::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn memos_are_recovered_from_the_full_transactions() {
+        let network = Network::MainNetwork;
+        let accounts = [Account::from_seed(&network, 0, PHRASE).unwrap()];
+        let (_, address) = accounts[0].keys.sapling.as_ref().unwrap().default_address();
+        let memo = MemoBytes::from_bytes(b"Thank you").unwrap();
+        let height = START_HEIGHT + 1;
+        let (tx, raw_tx) = sapling_tx(None, &[(address, 1000, memo.clone())], height);
+        let txid: Hash = tx.hash.clone().try_into().unwrap();
+        let mut result = scan(&accounts, vec![block(height, tx)]);
+        assert_eq!(result.sapling.notes.len(), 1);
+        assert!(result.sapling.notes[0].note.memo.is_none());
+
+        let mut source = MemoryTransactionSource::new(HashMap::from([(txid, raw_tx)]));
+        fetch_memos(&network, &accounts, &mut source, &mut result)
+            .await
+            .unwrap();
+        assert_eq!(result.sapling.notes[0].note.txid, txid);
+        assert_eq!(result.sapling.notes[0].note.memo, Some(memo));
+    }
+
+    #[tokio::test]
+    async fn sent_outputs_are_recovered_with_the_ovk() {
+        let network = Network::MainNetwork;
+        let accounts = [Account::from_seed(&network, 0, PHRASE).unwrap()];
+        let fvk = accounts[0].keys.sapling.as_ref().unwrap();
+        let (_, address) = fvk.default_address();
+        let other = Account::from_seed(&network, 1, PHRASE).unwrap();
+        let (_, other_address) = other.keys.sapling.as_ref().unwrap().default_address();
+
+        let (received_tx, _) = sapling_tx(
+            None,
+            &[(address, 1000, MemoBytes::empty())],
+            START_HEIGHT + 1,
+        );
+        let received = scan(
+            &accounts,
+            vec![block(START_HEIGHT + 1, received_tx.clone())],
+        );
+        let nf = received.sapling.notes[0].note.nf;
+
+        // the change is usually encrypted with the external ovk too
+        let memo = MemoBytes::from_bytes(b"For the pizza").unwrap();
+        let (mut spend_tx, raw_tx) = sapling_tx(
+            Some(fvk.to_ovk(Scope::External)),
+            &[
+                (other_address, 600, memo.clone()),
+                (address, 390, MemoBytes::empty()),
+            ],
+            START_HEIGHT + 2,
+        );
+        spend_tx.spends = vec![CompactSaplingSpend { nf: nf.to_vec() }];
+        let txid: Hash = spend_tx.hash.clone().try_into().unwrap();
+        let mut result = scan(
+            &accounts,
+            vec![
+                block(START_HEIGHT + 1, received_tx),
+                block(START_HEIGHT + 2, spend_tx),
+            ],
+        );
+        assert_eq!(result.sapling.spent_notes.len(), 1);
+
+        let mut source = MemoryTransactionSource::new(HashMap::from([(txid, raw_tx)]));
+        fetch_sent_outputs(&network, &accounts, &mut source, &mut result)
+            .await
+            .unwrap();
+        let sent = &result.sapling.sent_outputs;
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|o| o.txid == txid && o.account == 0));
+        assert_eq!(sent[0].value, 600);
+        assert_eq!(
+            sent[0].address,
+            RecipientAddress::Shielded(other_address).encode(&network)
+        );
+        assert_eq!(sent[0].memo, memo);
+        assert!(!sent[0].change);
+        assert_eq!(sent[1].value, 390);
+        assert!(sent[1].change);
+    }
+}
@@ -0,0 +1,282 @@
+use super::keys::Account;
+use super::lwd::get_mempool_txs;
+use super::note::ReceivedNote;
+use super::scan::{decrypt_mempool_txs, Pool, PreparedIvks, ScanResult};
+use super::Hash;
+use crate::lw_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::lw_rpc::CompactTx;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use tonic::transport::Channel;
+use zcash_primitives::consensus::Network;
+use zcash_primitives::zip32::Scope;
+
+/// A note received by one of the scanned accounts in a transaction
+/// that is not mined yet
+#[derive(Clone, Debug)]
+pub struct PendingNote {
+    pub pool: Pool,
+    pub account: u32,
+    pub scope: Scope,
+    pub value: u64,
+    pub txid: Hash,
+    pub output_index: u32,
+}
+
+/// An unspent note spent by a transaction that is not mined yet
+#[derive(Clone, Debug)]
+pub struct PendingSpend {
+    pub pool: Pool,
+    pub account: u32,
+    pub value: u64,
+    pub nf: Hash,
+    pub txid: Hash,
+}
+
+/// A mempool transaction with its notes and spends for the scanned accounts
+#[derive(Clone, Debug)]
+pub struct PendingTx {
+    pub received: Vec<PendingNote>,
+    pub spent: Vec<PendingSpend>,
+}
+
+/// Watches the mempool for transactions that concern the scanned accounts
+///
+/// The transactions are trial decrypted once, when they first appear in the
+/// mempool, and their spends are matched against the unspent notes of the
+/// last scan. They are dropped when they leave the mempool or when the
+/// block scan confirms them, see [MempoolWatcher::reconcile]
+pub struct MempoolWatcher {
+    network: Network,
+    ivks: PreparedIvks,
+    /// Every transaction of the mempool by txid, most of them do not
+    /// concern the accounts
+    txs: HashMap<Hash, PendingTx>,
+}
+
+impl MempoolWatcher {
+    pub fn new(network: &Network, accounts: &[Account]) -> Self {
+        MempoolWatcher {
+            network: *network,
+            ivks: PreparedIvks::new(accounts),
+            txs: HashMap::new(),
+        }
+    }
+
+    /// Replace the transactions by the content of the mempool of the
+    /// lightwalletd server
+    pub async fn poll(
+        &mut self,
+        client: &mut CompactTxStreamerClient<Channel>,
+        result: &ScanResult,
+    ) -> Result<()> {
+        let txs = get_mempool_txs(client).await?;
+        self.update(txs, result)
+    }
+
+    /// Replace the transactions by the mempool transactions `txs`
+    ///
+    /// Only the transactions that were not in the mempool before are
+    /// trial decrypted
+    pub fn update(&mut self, txs: Vec<CompactTx>, result: &ScanResult) -> Result<()> {
+        let mut new_txs = vec![];
+        let mut mempool = HashSet::new();
+        for tx in txs {
+            let txid: Hash = tx
+                .hash
+                .clone()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid txid"))?;
+            if mempool.insert(txid) && !self.txs.contains_key(&txid) {
+                new_txs.push((txid, tx));
+            }
+        }
+        self.txs.retain(|txid, _| mempool.contains(txid));
+
+        let sapling_nfs: HashMap<&[u8], &ReceivedNote> = result
+            .sapling
+            .notes
+            .iter()
+            .map(|n| (&n.note.nf[..], &n.note))
+            .collect();
+        let orchard_nfs: HashMap<&[u8], &ReceivedNote> = result
+            .orchard
+            .notes
+            .iter()
+            .map(|n| (&n.note.nf[..], &n.note))
+            .collect();
+        for (txid, tx) in new_txs.iter() {
+            let sapling_spends = tx
+                .spends
+                .iter()
+                .filter_map(|s| sapling_nfs.get(&*s.nf))
+                .map(|n| (Pool::Sapling, n));
+            let orchard_spends = tx
+                .actions
+                .iter()
+                .filter_map(|a| orchard_nfs.get(&*a.nullifier))
+                .map(|n| (Pool::Orchard, n));
+            let spent = sapling_spends
+                .chain(orchard_spends)
+                .map(|(pool, n)| {
+                    log::info!("Pending spend of {} for {}", n.value, n.account);
+                    PendingSpend {
+                        pool,
+                        account: n.account,
+                        value: n.value,
+                        nf: n.nf,
+                        txid: *txid,
+                    }
+                })
+                .collect();
+            self.txs.insert(
+                *txid,
+                PendingTx {
+                    received: vec![],
+                    spent,
+                },
+            );
+        }
+
+        let notes = decrypt_mempool_txs(
+            &self.network,
+            result.height + 1,
+            new_txs.into_iter().map(|(_, tx)| tx).collect(),
+            &self.ivks,
+        )?;
+        for n in notes {
+            log::info!("Pending receive of {} for {}", n.value, n.account);
+            self.txs.get_mut(&n.txid).unwrap().received.push(n);
+        }
+        Ok(())
+    }
+
+    /// Drop the transactions confirmed by the scan that gave `result`
+    ///
+    /// A transaction is confirmed when it created one of the notes
+    /// of the scan or spent one of them
+    pub fn reconcile(&mut self, result: &ScanResult) {
+        let mut mined = HashSet::new();
+        let unspent = result
+            .sapling
+            .notes
+            .iter()
+            .map(|n| &n.note)
+            .chain(result.orchard.notes.iter().map(|n| &n.note));
+        mined.extend(unspent.map(|n| n.txid));
+        for n in result
+            .sapling
+            .spent_notes
+            .iter()
+            .chain(result.orchard.spent_notes.iter())
+        {
+            mined.insert(n.note.txid);
+            mined.insert(n.spent_txid);
+        }
+        self.txs.retain(|txid, _| !mined.contains(txid));
+    }
+
+    /// The notes received by the pending transactions
+    pub fn pending_received(&self) -> impl Iterator<Item = &PendingNote> {
+        self.txs.values().flat_map(|tx| tx.received.iter())
+    }
+
+    /// The notes spent by the pending transactions
+    pub fn pending_spent(&self) -> impl Iterator<Item = &PendingSpend> {
+        self.txs.values().flat_map(|tx| tx.spent.iter())
+    }
+
+    /// The pending transactions that concern the accounts, by txid
+    pub fn pending_txs(&self) -> impl Iterator<Item = (&Hash, &PendingTx)> {
+        self.txs
+            .iter()
+            .filter(|(_, tx)| !tx.received.is_empty() || !tx.spent.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::scan::{scan_source, ScanHandle};
+    use super::super::source::MemorySource;
+    use super::super::state::SyncState;
+    use super::super::testing::{block_hash, sapling_output, PHRASE, START_HEIGHT};
+    use super::*;
+    use crate::lw_rpc::{CompactBlock, CompactSaplingSpend};
+
+    fn tx(id: u8, f: impl FnOnce(&mut CompactTx)) -> CompactTx {
+        let mut tx = CompactTx {
+            hash: vec![id; 32],
+            ..CompactTx::default()
+        };
+        f(&mut tx);
+        tx
+    }
+
+    fn scan(accounts: &[Account], txs: Vec<CompactTx>) -> ScanResult {
+        let blocks = txs
+            .into_iter()
+            .enumerate()
+            .map(|(i, tx)| {
+                let height = START_HEIGHT + 1 + i as u32;
+                CompactBlock {
+                    height: height as u64,
+                    hash: block_hash(height),
+                    prev_hash: block_hash(height - 1),
+                    vtx: vec![tx],
+                    ..CompactBlock::default()
+                }
+            })
+            .collect();
+        scan_source(
+            &Network::MainNetwork,
+            accounts,
+            MemorySource::new(blocks),
+            None,
+            SyncState::new(),
+            None,
+            &ScanHandle::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mined_transactions_are_reconciled() {
+        let network = Network::MainNetwork;
+        let accounts = [Account::from_seed(&network, 0, PHRASE).unwrap()];
+        let received = tx(1, |tx| {
+            tx.outputs = vec![sapling_output(&accounts[0], 1000, 1)]
+        });
+        let result = scan(&accounts, vec![received.clone()]);
+        let nf = result.sapling.notes[0].note.nf;
+
+        let incoming = tx(2, |tx| {
+            tx.outputs = vec![sapling_output(&accounts[0], 500, 2)]
+        });
+        let spend = tx(3, |tx| {
+            tx.spends = vec![CompactSaplingSpend { nf: nf.to_vec() }]
+        });
+        let other = tx(4, |_| ());
+        let mut watcher = MempoolWatcher::new(&network, &accounts);
+        watcher
+            .update(vec![incoming.clone(), spend.clone(), other], &result)
+            .unwrap();
+        let pending: Vec<_> = watcher
+            .pending_received()
+            .map(|n| (n.txid, n.value))
+            .collect();
+        assert_eq!(pending, [([2; 32], 500)]);
+        let pending: Vec<_> = watcher.pending_spent().map(|s| (s.txid, s.nf)).collect();
+        assert_eq!(pending, [([3; 32], nf)]);
+        assert_eq!(watcher.pending_txs().count(), 2);
+
+        // the incoming note is mined, the spend is still pending
+        let result = scan(&accounts, vec![received.clone(), incoming]);
+        watcher.reconcile(&result);
+        assert_eq!(watcher.pending_received().count(), 0);
+        assert_eq!(watcher.pending_spent().count(), 1);
+
+        let result = scan(&accounts, vec![received, spend]);
+        watcher.reconcile(&result);
+        assert_eq!(watcher.pending_txs().count(), 0);
+    }
+}
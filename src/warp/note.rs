@@ -0,0 +1,336 @@
+use super::{Hash, Hasher, Witness};
+use anyhow::{anyhow, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use ff::PrimeField;
+use std::io::{Read, Write};
+use zcash_primitives::memo::MemoBytes;
+use zcash_primitives::sapling::value::NoteValue;
+use zcash_primitives::sapling::{Note, PaymentAddress, Rseed};
+use zcash_primitives::zip32::Scope;
+
+/// A note received by one of the scanned accounts
+#[derive(Clone, Debug)]
+pub struct ReceivedNote {
+    pub account: u32,
+    pub scope: Scope,
+    pub height: u32,
+    pub position: u32,
+    pub value: u64,
+    pub diversifier: [u8; 11],
+    pub recipient: [u8; 43],
+    /// rseed after ZIP 212, rcm before
+    pub rseed: [u8; 32],
+    pub zip212: bool,
+    /// Orchard only: nullifier of the action that created the note
+    pub rho: Hash,
+    pub nf: Hash,
+    /// Transaction of the note and index of its output/action
+    pub txid: Hash,
+    pub output_index: u32,
+    /// Memo recovered by the full decryption of the transaction
+    pub memo: Option<MemoBytes>,
+}
+
+impl ReceivedNote {
+    pub fn from_sapling(
+        account: u32,
+        scope: Scope,
+        height: u32,
+        position: u32,
+        note: &Note,
+        nf: Hash,
+    ) -> Self {
+        let recipient = note.recipient();
+        let (rseed, zip212) = match note.rseed() {
+            Rseed::BeforeZip212(rcm) => (rcm.to_repr(), false),
+            Rseed::AfterZip212(rseed) => (*rseed, true),
+        };
+        ReceivedNote {
+            account,
+            scope,
+            height,
+            position,
+            value: note.value().inner(),
+            diversifier: recipient.diversifier().0,
+            recipient: recipient.to_bytes(),
+            rseed,
+            zip212,
+            rho: [0u8; 32],
+            nf,
+            txid: [0u8; 32],
+            output_index: 0,
+            memo: None,
+        }
+    }
+
+    pub fn from_orchard(
+        account: u32,
+        scope: Scope,
+        height: u32,
+        position: u32,
+        note: &orchard::Note,
+        nf: Hash,
+    ) -> Self {
+        let recipient = note.recipient();
+        ReceivedNote {
+            account,
+            scope,
+            height,
+            position,
+            value: note.value().inner(),
+            diversifier: *recipient.diversifier().as_array(),
+            recipient: recipient.to_raw_address_bytes(),
+            rseed: *note.rseed().as_bytes(),
+            zip212: true,
+            rho: note.rho().to_bytes(),
+            nf,
+            txid: [0u8; 32],
+            output_index: 0,
+            memo: None,
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LE>(self.account)?;
+        write_scope(self.scope, &mut w)?;
+        w.write_u32::<LE>(self.height)?;
+        w.write_u32::<LE>(self.position)?;
+        w.write_u64::<LE>(self.value)?;
+        w.write_all(&self.diversifier)?;
+        w.write_all(&self.recipient)?;
+        w.write_all(&self.rseed)?;
+        w.write_u8(self.zip212 as u8)?;
+        w.write_all(&self.rho)?;
+        w.write_all(&self.nf)?;
+        w.write_all(&self.txid)?;
+        w.write_u32::<LE>(self.output_index)?;
+        match self.memo.as_ref() {
+            Some(memo) => {
+                w.write_u8(1)?;
+                w.write_all(memo.as_slice())?;
+            }
+            None => w.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let account = r.read_u32::<LE>()?;
+        let scope = read_scope(&mut r)?;
+        let height = r.read_u32::<LE>()?;
+        let position = r.read_u32::<LE>()?;
+        let value = r.read_u64::<LE>()?;
+        let mut diversifier = [0u8; 11];
+        r.read_exact(&mut diversifier)?;
+        let mut recipient = [0u8; 43];
+        r.read_exact(&mut recipient)?;
+        let mut rseed = [0u8; 32];
+        r.read_exact(&mut rseed)?;
+        let zip212 = r.read_u8()? != 0;
+        let mut rho = [0u8; 32];
+        r.read_exact(&mut rho)?;
+        let mut nf = [0u8; 32];
+        r.read_exact(&mut nf)?;
+        let mut txid = [0u8; 32];
+        r.read_exact(&mut txid)?;
+        let output_index = r.read_u32::<LE>()?;
+        let memo = match r.read_u8()? {
+            0 => None,
+            _ => {
+                let mut memo = [0u8; 512];
+                r.read_exact(&mut memo)?;
+                Some(MemoBytes::from_bytes(&memo).map_err(|_| anyhow!("Invalid memo"))?)
+            }
+        };
+        Ok(ReceivedNote {
+            account,
+            scope,
+            height,
+            position,
+            value,
+            diversifier,
+            recipient,
+            rseed,
+            zip212,
+            rho,
+            nf,
+            txid,
+            output_index,
+            memo,
+        })
+    }
+
+    /// Rebuild the sapling note
+    pub fn note(&self) -> Result<Note> {
+        let recipient = PaymentAddress::from_bytes(&self.recipient)
+            .ok_or(anyhow!("Invalid recipient address"))?;
+        let rseed = if self.zip212 {
+            Rseed::AfterZip212(self.rseed)
+        } else {
+            let rcm =
+                Option::from(jubjub::Fr::from_repr(self.rseed)).ok_or(anyhow!("Invalid rcm"))?;
+            Rseed::BeforeZip212(rcm)
+        };
+        Ok(Note::from_parts(
+            recipient,
+            NoteValue::from_raw(self.value),
+            rseed,
+        ))
+    }
+
+    /// Rebuild the orchard note
+    pub fn orchard_note(&self) -> Result<orchard::Note> {
+        let recipient = Option::from(orchard::Address::from_raw_address_bytes(&self.recipient))
+            .ok_or(anyhow!("Invalid recipient address"))?;
+        let rho = Option::from(orchard::note::Nullifier::from_bytes(&self.rho))
+            .ok_or(anyhow!("Invalid rho"))?;
+        let rseed = Option::from(orchard::note::RandomSeed::from_bytes(self.rseed, &rho))
+            .ok_or(anyhow!("Invalid rseed"))?;
+        let note = orchard::Note::from_parts(
+            recipient,
+            orchard::value::NoteValue::from_raw(self.value),
+            rho,
+            rseed,
+        );
+        Option::from(note).ok_or(anyhow!("Invalid orchard note"))
+    }
+}
+
+fn write_scope<W: Write>(scope: Scope, mut w: W) -> Result<()> {
+    w.write_u8(match scope {
+        Scope::External => 0,
+        Scope::Internal => 1,
+    })?;
+    Ok(())
+}
+
+fn read_scope<R: Read>(mut r: R) -> Result<Scope> {
+    match r.read_u8()? {
+        0 => Ok(Scope::External),
+        1 => Ok(Scope::Internal),
+        s => anyhow::bail!("Invalid scope {s}"),
+    }
+}
+
+/// An unspent note with its witness at the final height
+#[derive(Debug)]
+pub struct UnspentNote<H: Hasher> {
+    pub note: ReceivedNote,
+    pub witness: Witness<H>,
+}
+
+/// A note that was spent during the scan
+#[derive(Clone, Debug)]
+pub struct SpentNote {
+    pub note: ReceivedNote,
+    pub spent_height: u32,
+    /// Transaction that spent the note
+    pub spent_txid: Hash,
+}
+
+impl SpentNote {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        self.note.write(&mut w)?;
+        w.write_u32::<LE>(self.spent_height)?;
+        w.write_all(&self.spent_txid)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let note = ReceivedNote::read(&mut r)?;
+        let spent_height = r.read_u32::<LE>()?;
+        let mut spent_txid = [0u8; 32];
+        r.read_exact(&mut spent_txid)?;
+        Ok(SpentNote {
+            note,
+            spent_height,
+            spent_txid,
+        })
+    }
+}
+
+/// An output of a transaction that spent notes of one of the scanned
+/// accounts, recovered with the outgoing viewing key of the account
+#[derive(Clone, Debug)]
+pub struct SentOutput {
+    pub account: u32,
+    pub height: u32,
+    pub txid: Hash,
+    /// Index of the output/action in the transaction
+    pub output_index: u32,
+    /// Encoded recipient address, a unified address for orchard
+    pub address: String,
+    pub value: u64,
+    pub memo: MemoBytes,
+    /// The recipient is an address of the account, i.e. change that
+    /// goes back to it
+    pub change: bool,
+}
+
+/// A transparent output received by one of the scanned accounts
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    pub account: u32,
+    /// Chain of the address that received the output, change if internal
+    pub scope: Scope,
+    /// Child index of the address in its chain
+    pub address_index: u32,
+    pub height: u32,
+    pub txid: Hash,
+    pub vout: u32,
+    pub value: u64,
+}
+
+impl Utxo {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LE>(self.account)?;
+        write_scope(self.scope, &mut w)?;
+        w.write_u32::<LE>(self.address_index)?;
+        w.write_u32::<LE>(self.height)?;
+        w.write_all(&self.txid)?;
+        w.write_u32::<LE>(self.vout)?;
+        w.write_u64::<LE>(self.value)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let account = r.read_u32::<LE>()?;
+        let scope = read_scope(&mut r)?;
+        let address_index = r.read_u32::<LE>()?;
+        let height = r.read_u32::<LE>()?;
+        let mut txid = [0u8; 32];
+        r.read_exact(&mut txid)?;
+        let vout = r.read_u32::<LE>()?;
+        let value = r.read_u64::<LE>()?;
+        Ok(Utxo {
+            account,
+            scope,
+            address_index,
+            height,
+            txid,
+            vout,
+            value,
+        })
+    }
+}
+
+/// A transparent output that was spent during the scan
+#[derive(Debug)]
+pub struct SpentUtxo {
+    pub utxo: Utxo,
+    pub spent_height: u32,
+}
+
+impl SpentUtxo {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        self.utxo.write(&mut w)?;
+        w.write_u32::<LE>(self.spent_height)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let utxo = Utxo::read(&mut r)?;
+        let spent_height = r.read_u32::<LE>()?;
+        Ok(SpentUtxo { utxo, spent_height })
+    }
+}
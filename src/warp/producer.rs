@@ -0,0 +1,225 @@
+use super::data::{DataFileHeader, DataFileWriter, TreeFrontier};
+use super::hasher::{check_nodes, OrchardHasher, SaplingHasher};
+use super::source::BlockSource;
+use super::{Bridge, Hash, Hasher, MerkleTree};
+use crate::lw_rpc::{CompactBlock, CompactTx};
+use anyhow::Result;
+use std::io::{Seek, Write};
+use zcash_primitives::consensus::Network;
+
+/// Converts plain compact blocks into warp blocks
+///
+/// Transactions with more than `spam_threshold` inputs/outputs/actions
+/// lose their outputs and actions, which are replaced by a bridge.
+/// Every block also gets a bridge over all its outputs/actions so that
+/// the scanner can skip it entirely when it has no new notes.
+///
+/// The spends of pruned transactions are kept for spend detection, as well
+/// as their actions without the commitment, ephemeral key and ciphertext
+pub struct WarpProducer {
+    spam_threshold: usize,
+    sapling: MerkleTree<SaplingHasher>,
+    orchard: MerkleTree<OrchardHasher>,
+}
+
+impl WarpProducer {
+    /// The commitment trees must be empty, i.e. the first block must be
+    /// at the activation of sapling
+    pub fn new(spam_threshold: usize) -> Self {
+        WarpProducer {
+            spam_threshold,
+            sapling: MerkleTree::empty(SaplingHasher::default()),
+            orchard: MerkleTree::empty(OrchardHasher::default()),
+        }
+    }
+
+    fn is_spam(&self, tx: &CompactTx) -> bool {
+        tx.spends.len() > self.spam_threshold
+            || tx.outputs.len() > self.spam_threshold
+            || tx.actions.len() > self.spam_threshold
+    }
+
+    pub fn convert(&mut self, mut block: CompactBlock) -> Result<CompactBlock> {
+        let height = block.height as u32;
+        let mut sapling_bridge: Option<Bridge<SaplingHasher>> = None;
+        let mut orchard_bridge: Option<Bridge<OrchardHasher>> = None;
+        for tx in block.vtx.iter_mut() {
+            let cmus = tx
+                .outputs
+                .iter()
+                .map(|o| o.cmu.clone().try_into())
+                .collect::<Result<Vec<Hash>, _>>()
+                .map_err(|_| anyhow::anyhow!("Invalid cmu"))?;
+            let cmxs = tx
+                .actions
+                .iter()
+                .map(|a| a.cmx.clone().try_into())
+                .collect::<Result<Vec<Hash>, _>>()
+                .map_err(|_| anyhow::anyhow!("Invalid cmx"))?;
+            check_nodes(&self.sapling.h, &cmus)?;
+            check_nodes(&self.orchard.h, &cmxs)?;
+            let tx_sapling_bridge = add_nodes(&mut self.sapling, height, &cmus);
+            let tx_orchard_bridge = add_nodes(&mut self.orchard, height, &cmxs);
+
+            if self.is_spam(tx) {
+                tx.outputs.clear();
+                // keep the nullifiers for spend detection
+                for a in tx.actions.iter_mut() {
+                    a.cmx.clear();
+                    a.ephemeral_key.clear();
+                    a.ciphertext.clear();
+                }
+                tx.sapling_bridge = tx_sapling_bridge
+                    .as_ref()
+                    .map(|b| encode_bridge(b, &self.sapling.h))
+                    .transpose()?;
+                tx.orchard_bridge = tx_orchard_bridge
+                    .as_ref()
+                    .map(|b| encode_bridge(b, &self.orchard.h))
+                    .transpose()?;
+            }
+            merge_bridge(&mut sapling_bridge, tx_sapling_bridge, &self.sapling.h);
+            merge_bridge(&mut orchard_bridge, tx_orchard_bridge, &self.orchard.h);
+        }
+        block.sapling_bridge = sapling_bridge
+            .as_ref()
+            .map(|b| encode_bridge(b, &self.sapling.h))
+            .transpose()?;
+        block.orchard_bridge = orchard_bridge
+            .as_ref()
+            .map(|b| encode_bridge(b, &self.orchard.h))
+            .transpose()?;
+        Ok(block)
+    }
+}
+
+fn add_nodes<H: Hasher<D = Hash>>(
+    tree: &mut MerkleTree<H>,
+    height: u32,
+    cms: &[Hash],
+) -> Option<Bridge<H>> {
+    if cms.is_empty() {
+        return None;
+    }
+    let nodes: Vec<_> = cms.iter().map(|cm| (*cm, false)).collect();
+    Some(tree.add_nodes(height, nodes.len() as u32, &nodes))
+}
+
+fn merge_bridge<H: Hasher>(acc: &mut Option<Bridge<H>>, bridge: Option<Bridge<H>>, h: &H) {
+    if let Some(bridge) = bridge {
+        match acc {
+            Some(acc) => acc.merge(&bridge, h),
+            None => *acc = Some(bridge),
+        }
+    }
+}
+
+fn encode_bridge<H: Hasher>(bridge: &Bridge<H>, h: &H) -> Result<crate::lw_rpc::Bridge> {
+    let mut data = vec![];
+    bridge.write(&mut data, h)?;
+    Ok(crate::lw_rpc::Bridge {
+        len: bridge.len as u32,
+        data,
+    })
+}
+
+/// Convert all the blocks of `source` and write them to `w`
+/// as a warp data file for `network`
+///
+/// Returns the header of the data file
+pub fn produce<S: BlockSource, W: Write + Seek>(
+    mut source: S,
+    w: W,
+    network: Network,
+    spam_threshold: usize,
+) -> Result<DataFileHeader> {
+    let mut producer = WarpProducer::new(spam_threshold);
+    let mut writer = DataFileWriter::new(w, network, spam_threshold as u32)?;
+    while let Some(block) = source.next_block()? {
+        let block = producer.convert(block)?;
+        let height = block.height as u32;
+        if height.is_multiple_of(100_000) {
+            log::info!("Height {height}");
+        }
+        writer.write_block(&block)?;
+    }
+    writer.finish(
+        TreeFrontier::from_tree(&producer.sapling)?,
+        TreeFrontier::from_tree(&producer.orchard)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warp::data::DataFileReader;
+    use crate::warp::empty_roots;
+    use crate::warp::keys::Account;
+    use crate::warp::scan::{scan_source, PoolResult, ScanHandle, ScanResult};
+    use crate::warp::source::MemorySource;
+    use crate::warp::state::SyncState;
+    use crate::warp::testing::{blocks, PHRASE};
+    use std::io::Cursor;
+
+    fn scan<S: BlockSource + 'static>(accounts: &[Account], source: S) -> ScanResult {
+        scan_source(
+            &Network::MainNetwork,
+            accounts,
+            source,
+            None,
+            SyncState::new(),
+            None,
+            &ScanHandle::default(),
+        )
+        .unwrap()
+    }
+
+    /// Positions and roots of the witnesses of the notes
+    fn roots<H: Hasher<D = Hash>>(result: &PoolResult<H>) -> Vec<(u32, Hash)> {
+        let h = H::default();
+        let er = empty_roots(&h);
+        result
+            .notes
+            .iter()
+            .map(|n| (n.note.position, n.witness.root(&er, &result.edge, &h).0))
+            .collect()
+    }
+
+    #[test]
+    fn produced_file_scans_like_the_plain_blocks() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let blocks = blocks(&account, 40);
+        let mut data = Cursor::new(vec![]);
+        let header = produce(
+            MemorySource::new(blocks.clone()),
+            &mut data,
+            Network::MainNetwork,
+            1,
+        )
+        .unwrap();
+        data.set_position(0);
+        let mut reader = DataFileReader::open(data).unwrap();
+        let mut produced = vec![];
+        while let Some(block) = reader.next_block().unwrap() {
+            produced.push(block);
+        }
+        let txs = produced.iter().flat_map(|b| b.vtx.iter());
+        assert!(txs.clone().any(|tx| tx.sapling_bridge.is_some()));
+        assert!(txs.clone().any(|tx| tx.orchard_bridge.is_some()));
+        assert!(txs.clone().any(|tx| !tx.outputs.is_empty()));
+        let accounts = [account];
+        let bridged = scan(&accounts, MemorySource::new(produced));
+        let plain = scan(&accounts, MemorySource::new(blocks));
+
+        assert_eq!(bridged.height, header.end_height);
+        header.sapling.check(&bridged.sapling.tree).unwrap();
+        header.orchard.check(&bridged.orchard.tree).unwrap();
+        assert_eq!(bridged.sapling.anchor, plain.sapling.anchor);
+        assert_eq!(bridged.orchard.anchor, plain.orchard.anchor);
+        assert_eq!(bridged.sapling.notes.len(), 8);
+        assert_eq!(roots(&bridged.sapling), roots(&plain.sapling));
+        assert!(roots(&bridged.sapling)
+            .iter()
+            .all(|(_, root)| *root == plain.sapling.anchor));
+    }
+}
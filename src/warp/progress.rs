@@ -0,0 +1,173 @@
+use super::scan::trial_actions;
+use crate::lw_rpc::CompactBlock;
+use allo_isolate::ffi::DartCObject;
+use allo_isolate::IntoDart;
+use prost::Message;
+use std::time::{Duration, Instant};
+
+/// Progress of a scan, reported after every chunk of blocks
+#[derive(Clone, Debug, Default)]
+pub struct ScanProgress {
+    /// Height of the last block scanned
+    pub height: u32,
+    /// Height where the scan stops, if known
+    pub target_height: Option<u32>,
+    /// Size of the compact blocks scanned, protobuf encoded. It is not
+    /// the number of bytes read from the source, which has its own framing
+    pub block_bytes: u64,
+    pub blocks: u64,
+    /// Sapling outputs and orchard actions trial decrypted
+    pub outputs: u64,
+    pub notes: u64,
+    pub spends: u64,
+    pub elapsed: Duration,
+    /// Estimated time until the target height, from the rate so far
+    pub eta: Option<Duration>,
+}
+
+/// Sent to Dart as an Int64List of the fields in order, with durations in
+/// milliseconds and -1 for the unknown target height and ETA
+impl IntoDart for ScanProgress {
+    fn into_dart(self) -> DartCObject {
+        vec![
+            self.height as i64,
+            self.target_height.map(i64::from).unwrap_or(-1),
+            self.block_bytes as i64,
+            self.blocks as i64,
+            self.outputs as i64,
+            self.notes as i64,
+            self.spends as i64,
+            self.elapsed.as_millis() as i64,
+            self.eta.map(|eta| eta.as_millis() as i64).unwrap_or(-1),
+        ]
+        .into_dart()
+    }
+}
+
+/// Receives the progress of a scan
+pub trait ProgressListener: Send + Sync {
+    fn on_progress(&self, progress: &ScanProgress);
+}
+
+impl<F: Fn(&ScanProgress) + Send + Sync> ProgressListener for F {
+    fn on_progress(&self, progress: &ScanProgress) {
+        self(progress)
+    }
+}
+
+/// Accumulates the progress of a scan chunk by chunk
+pub(super) struct ProgressTracker {
+    /// Height before the first block scanned, known with the first chunk
+    start_height: Option<u32>,
+    start_time: Instant,
+    progress: ScanProgress,
+}
+
+impl ProgressTracker {
+    /// Scan of the blocks up to `target_height`
+    pub(super) fn new(target_height: Option<u32>) -> Self {
+        ProgressTracker {
+            start_height: None,
+            start_time: Instant::now(),
+            progress: ScanProgress {
+                target_height,
+                ..ScanProgress::default()
+            },
+        }
+    }
+
+    /// Add a chunk of `blocks` where `notes` were received and `spends` spent
+    pub(super) fn add_chunk(
+        &mut self,
+        blocks: &[CompactBlock],
+        notes: usize,
+        spends: usize,
+    ) -> &ScanProgress {
+        let p = &mut self.progress;
+        if let Some(b) = blocks.first() {
+            // a fresh scan starts at the first block of the data file,
+            // not at height 0
+            self.start_height
+                .get_or_insert((b.height as u32).saturating_sub(1));
+        }
+        for b in blocks.iter() {
+            p.block_bytes += b.encoded_len() as u64;
+            p.outputs += b
+                .vtx
+                .iter()
+                .map(|tx| (tx.outputs.len() + trial_actions(tx).len()) as u64)
+                .sum::<u64>();
+        }
+        p.blocks += blocks.len() as u64;
+        p.notes += notes as u64;
+        p.spends += spends as u64;
+        if let Some(b) = blocks.last() {
+            p.height = b.height as u32;
+        }
+        p.elapsed = self.start_time.elapsed();
+        p.eta = p.target_height.and_then(|target_height| {
+            let done = p.height.checked_sub(self.start_height?)?;
+            let remaining = target_height.saturating_sub(p.height);
+            if done == 0 {
+                return None;
+            }
+            Some(p.elapsed.mul_f64(remaining as f64 / done as f64))
+        });
+        p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(start: u32, end: u32) -> Vec<CompactBlock> {
+        (start..=end)
+            .map(|height| CompactBlock {
+                height: height as u64,
+                ..CompactBlock::default()
+            })
+            .collect()
+    }
+
+    fn assert_close(a: Duration, b: Duration) {
+        assert!(
+            (a.as_secs_f64() - b.as_secs_f64()).abs() < 0.01,
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn eta_from_the_rate_since_the_first_block() {
+        let mut tracker = ProgressTracker::new(Some(2_000_100));
+        tracker.start_time = Instant::now() - Duration::from_secs(10);
+
+        // half of the blocks in the elapsed time
+        let p = tracker
+            .add_chunk(&blocks(2_000_001, 2_000_050), 1, 0)
+            .clone();
+        assert_eq!(p.height, 2_000_050);
+        assert_eq!(p.blocks, 50);
+        assert_eq!(p.notes, 1);
+        assert_close(p.eta.unwrap(), p.elapsed);
+
+        // three quarters
+        let p = tracker
+            .add_chunk(&blocks(2_000_051, 2_000_075), 0, 1)
+            .clone();
+        assert_eq!(p.blocks, 75);
+        assert_eq!(p.spends, 1);
+        assert_close(p.eta.unwrap(), p.elapsed / 3);
+
+        let p = tracker.add_chunk(&blocks(2_000_076, 2_000_100), 0, 0);
+        assert_eq!(p.eta, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn no_eta_without_target() {
+        let mut tracker = ProgressTracker::new(None);
+        let p = tracker.add_chunk(&blocks(1_000, 1_010), 0, 0);
+        assert_eq!(p.height, 1_010);
+        assert_eq!(p.eta, None);
+    }
+}
@@ -1,216 +1,1028 @@
-use super::hasher::SaplingHasher;
-use super::{Bridge, MerkleTree};
+use super::data::DataFileHeader;
+use super::hasher::{check_nodes, OrchardHasher, SaplingHasher};
+use super::keys::Account;
+use super::lwd::{connect_lightwalletd, get_latest_height};
+use super::mempool::PendingNote;
+use super::note::{ReceivedNote, SentOutput, SpentNote, SpentUtxo, UnspentNote, Utxo};
+use super::progress::{ProgressListener, ProgressTracker, ScanProgress};
+use super::source::{BlockSource, HttpSource, LwdSource, LwdTransactionSource, MmapSource};
+use super::state::{PoolState, SyncState, TransparentState};
+use super::transparent::scan_transparent_txs;
+use super::{Bridge, Hash, Hasher, MerkleTree, DEPTH};
+use crate::lw_rpc::{CompactBlock, CompactOrchardAction, CompactTx, TreeState};
+use allo_isolate::IntoDart;
 use anyhow::Result;
-use byteorder::{ReadBytesExt, LE};
-use prost::Message;
+use orchard::keys::PreparedIncomingViewingKey as PreparedOrchardIvk;
+use orchard::note::{ExtractedNoteCommitment, Nullifier};
+use orchard::note_encryption::{CompactAction, OrchardDomain};
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::io::Read;
 use std::marker::PhantomData;
-use std::slice;
-use std::sync::mpsc::channel;
-use std::time::Instant;
-use allo_isolate::IntoDart;
-use zcash_client_backend::encoding::decode_extended_full_viewing_key;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use zcash_note_encryption::batch::try_compact_note_decryption;
 use zcash_note_encryption::{EphemeralKeyBytes, ShieldedOutput};
-use zcash_primitives::consensus::{BlockHeight, Network, Parameters};
+use zcash_primitives::consensus::{BlockHeight, Network, NetworkUpgrade, Parameters};
+use zcash_primitives::merkle_tree::{Hashable, MerklePath};
 use zcash_primitives::sapling::note_encryption::{PreparedIncomingViewingKey, SaplingDomain};
-use zcash_primitives::sapling::Note;
-use crate::lw_rpc::CompactBlock;
-
-pub async fn full_scan(network: &Network, url: &str, fvk: &str, port: i64) -> Result<u64> {
-    let zfvk =
-        decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), fvk)
-            .unwrap();
-    let nk = &zfvk.fvk.vk.nk;
-    let ivk = zfvk.fvk.vk.ivk();
-    let pivk = PreparedIncomingViewingKey::new(&ivk);
-    let response = ureq::get(url).call()?;
-    let mut reader = response.into_reader();
-    let final_height = 2166554;
-    let mut height;
-    let mut tx_count = 0;
-    let mut pos = 0;
-    let mut cmtree = MerkleTree::empty(SaplingHasher::default());
-    let mut nfs: HashMap<[u8; 32], (u32, u64)> = HashMap::new();
-    let mut balance = 0i64;
+use zcash_primitives::sapling::{Note, NullifierDerivingKey};
+use zcash_primitives::zip32::Scope;
 
-    let start_time = Instant::now();
+/// Shielded pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pool {
+    Sapling,
+    Orchard,
+}
 
-    let (tx_blocks, rx_blocks) = channel::<Vec<CompactBlock>>();
-    std::thread::spawn(move || {
-        let mut block_chunk = vec![];
-        while let Ok(len) = reader.read_u32::<LE>() {
-            let mut buf = vec![0; len as usize];
-            reader.read_exact(&mut buf)?;
-            let cb: CompactBlock = CompactBlock::decode(&*buf)?;
-            let height = cb.height;
-            tx_count += cb.vtx.len();
-            block_chunk.push(cb);
-            if tx_count > 100_000 || height == final_height {
-                let blocks = block_chunk;
-                block_chunk = vec![];
-                tx_count = 0;
-                tx_blocks.send(blocks)?;
-            }
+impl Pool {
+    fn block_bridge(self, b: &CompactBlock) -> Option<&crate::lw_rpc::Bridge> {
+        match self {
+            Pool::Sapling => b.sapling_bridge.as_ref(),
+            Pool::Orchard => b.orchard_bridge.as_ref(),
         }
-        Ok::<_, anyhow::Error>(())
-    });
+    }
+
+    fn tx_bridge(self, tx: &CompactTx) -> Option<&crate::lw_rpc::Bridge> {
+        match self {
+            Pool::Sapling => tx.sapling_bridge.as_ref(),
+            Pool::Orchard => tx.orchard_bridge.as_ref(),
+        }
+    }
+
+    /// Note commitments of the transaction, cmu for sapling and cmx for orchard
+    fn tx_commitments(self, tx: &CompactTx) -> Result<Vec<Hash>> {
+        match self {
+            Pool::Sapling => tx
+                .outputs
+                .iter()
+                .map(|o| to_array(&o.cmu, "sapling cmu"))
+                .collect(),
+            Pool::Orchard => tx
+                .actions
+                .iter()
+                .map(|a| to_array(&a.cmx, "orchard cmx"))
+                .collect(),
+        }
+    }
+
+    /// Nullifiers of the transaction, including those of the pruned actions
+    fn tx_nullifiers(self, tx: &CompactTx) -> Vec<&[u8]> {
+        match self {
+            Pool::Sapling => tx.spends.iter().map(|s| &*s.nf).collect(),
+            Pool::Orchard => tx.actions.iter().map(|a| &*a.nullifier).collect(),
+        }
+    }
+}
+
+/// Orchard actions of the transaction to trial decrypt
+///
+/// The actions of a pruned transaction only keep their nullifiers,
+/// its commitments are in its bridge
+pub(super) fn trial_actions(tx: &CompactTx) -> &[CompactOrchardAction] {
+    if tx.orchard_bridge.is_some() {
+        &[]
+    } else {
+        &tx.actions
+    }
+}
+
+/// The blocks do not connect to the blocks scanned previously
+#[derive(Debug)]
+pub struct ReorgError {
+    /// Height of the first scanned block that is no longer on the chain
+    pub height: u32,
+}
+
+impl std::fmt::Display for ReorgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Reorg detected at height {}", self.height)
+    }
+}
+
+impl std::error::Error for ReorgError {}
+
+/// Outcome of a scan for a shielded pool
+#[derive(Debug)]
+pub struct PoolResult<H: Hasher> {
+    /// Root of the note commitment tree at the final height
+    pub anchor: Hash,
+    /// Right edge of the tree, needed to complete the witnesses
+    pub edge: [Hash; DEPTH],
+    pub notes: Vec<UnspentNote<H>>,
+    pub spent_notes: Vec<SpentNote>,
+    /// Outputs of the transactions that spent the notes, filled by
+    /// [super::memo::fetch_sent_outputs]
+    pub sent_outputs: Vec<SentOutput>,
+    /// Note commitment tree with its checkpoints, to get the anchors and
+    /// the witnesses of the last blocks with [MerkleTree::anchor_at]
+    /// and [MerkleTree::witness_root_at]
+    pub tree: MerkleTree<H>,
+}
+
+impl<H: Hasher> PoolResult<H> {
+    pub fn balance(&self) -> u64 {
+        self.notes.iter().map(|n| n.note.value).sum()
+    }
+
+    pub fn account_balance(&self, account: u32) -> u64 {
+        self.notes
+            .iter()
+            .filter(|n| n.note.account == account)
+            .map(|n| n.note.value)
+            .sum()
+    }
+}
+
+impl<H: Hasher<D = Hash>> PoolResult<H> {
+    /// Merkle path of an unspent note to the anchor
+    pub fn merkle_path<N: Hashable>(&self, note: &UnspentNote<H>, h: &H) -> Result<MerklePath<N>> {
+        let er = super::empty_roots(h);
+        note.witness.to_merkle_path(&er, &self.edge, h)
+    }
+}
+
+/// Outcome of a scan for the transparent addresses
+#[derive(Debug)]
+pub struct TransparentResult {
+    /// Height of the last block whose transactions were scanned
+    pub height: u32,
+    pub utxos: Vec<Utxo>,
+    pub spent_utxos: Vec<SpentUtxo>,
+}
+
+impl TransparentResult {
+    pub fn balance(&self) -> u64 {
+        self.utxos.iter().map(|u| u.value).sum()
+    }
+
+    pub fn account_balance(&self, account: u32) -> u64 {
+        self.utxos
+            .iter()
+            .filter(|u| u.account == account)
+            .map(|u| u.value)
+            .sum()
+    }
+}
+
+/// Outcome of a scan
+#[derive(Debug)]
+pub struct ScanResult {
+    /// Height of the last block scanned
+    pub height: u32,
+    pub balance: u64,
+    pub sapling: PoolResult<SaplingHasher>,
+    pub orchard: PoolResult<OrchardHasher>,
+    pub transparent: TransparentResult,
+    /// The scan was stopped by [ScanHandle::cancel] before the end of
+    /// the blocks, at `height`
+    pub cancelled: bool,
+}
 
-    while let Ok(block_chunk) = rx_blocks.recv() {
-        height = block_chunk[0].height;
-        println!("\x1b[0;31mHeight: {height}\x1b[0m");
+impl ScanResult {
+    pub fn account_balance(&self, account: u32) -> u64 {
+        self.sapling.account_balance(account)
+            + self.orchard.account_balance(account)
+            + self.transparent.account_balance(account)
+    }
+}
+
+/// Scan the warp data file at `url` with the given viewing key
+///
+/// `fvk` is either a sapling extended full viewing key or a unified full viewing key.
+/// The notes are attributed to account 0.
+/// See [scan_accounts]
+pub fn full_scan(
+    network: &Network,
+    url: &str,
+    fvk: &str,
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+) -> Result<ScanResult> {
+    let account = Account::decode(network, 0, fvk)?;
+    scan_accounts(network, url, &[account], state_file, handle)
+}
+
+/// Cancels, pauses and resumes a running scan
+///
+/// The scan checks the handle between two chunks of blocks, after the state
+/// is saved. A cancelled scan returns the result at the last chunk with
+/// [ScanResult::cancelled] set, without waiting for the block being read.
+/// It can be resumed from the state file.
+///
+/// The progress is reported to the listener of the handle and posted
+/// to its Dart port after every chunk
+#[derive(Clone, Default)]
+pub struct ScanHandle {
+    /// Dart port that receives the progress, 0 if none
+    port: i64,
+    listener: Option<Arc<dyn ProgressListener>>,
+    /// Checkpoint window of the sync state, the one of the state if none
+    max_checkpoints: Option<usize>,
+    control: Arc<ScanControl>,
+}
+
+#[derive(Debug, Default)]
+struct ScanControl {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl ScanHandle {
+    pub fn new(port: i64) -> Self {
+        ScanHandle {
+            port,
+            listener: None,
+            max_checkpoints: None,
+            control: Arc::default(),
+        }
+    }
+
+    pub fn with_listener(port: i64, listener: Arc<dyn ProgressListener>) -> Self {
+        ScanHandle {
+            listener: Some(listener),
+            ..ScanHandle::new(port)
+        }
+    }
+
+    pub fn port(&self) -> i64 {
+        self.port
+    }
+
+    /// Handle that controls the same scan and posts its progress to `port`
+    pub fn with_port(&self, port: i64) -> Self {
+        ScanHandle {
+            port,
+            ..self.clone()
+        }
+    }
+
+    /// Keep the last `max_checkpoints` blocks of the scan as checkpoints,
+    /// i.e. the maximum depth of a reorg and of an anchor. It is stored
+    /// in the state file
+    pub fn with_max_checkpoints(self, max_checkpoints: usize) -> Self {
+        ScanHandle {
+            max_checkpoints: Some(max_checkpoints),
+            ..self
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.control.cancelled.store(true, Ordering::Release);
+        // wake up a paused scan
+        let _paused = self.control.paused.lock().unwrap();
+        self.control.resumed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.control.cancelled.load(Ordering::Acquire)
+    }
+
+    pub fn pause(&self) {
+        *self.control.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.control.paused.lock().unwrap() = false;
+        self.control.resumed.notify_all();
+    }
+
+    fn report(&self, progress: &ScanProgress) {
+        if let Some(listener) = self.listener.as_ref() {
+            listener.on_progress(progress);
+        }
         unsafe {
             if let Some(post) = crate::api::POST_COBJ {
-                post(port, &mut height.into_dart());
+                post(self.port, &mut progress.clone().into_dart());
             }
         }
-        let dec_block_chunk: Vec<_> = block_chunk
-            .par_iter()
-            .map(|b| decrypt_block(network, b, &pivk).unwrap())
-            .collect();
+    }
 
-        let mut notes = vec![];
-        let mut bridges: Option<Bridge<SaplingHasher>> = None;
-        let mut pos_start = pos;
-        for db in dec_block_chunk.iter() {
-            for n in db.notes.iter() {
-                let note = &n.1;
-                let p = pos + n.0;
-                let nf = note.nf(nk, p as u64);
-                let nv = note.value().inner();
-                balance += nv as i64;
-                nfs.insert(nf.0, (p, nv));
-                notes.push((p, n.1.clone()));
-            }
-            pos += db.count_outputs;
+    /// Block while the scan is paused and not cancelled
+    fn wait_if_paused(&self) {
+        let mut paused = self.control.paused.lock().unwrap();
+        while *paused && !self.is_cancelled() {
+            paused = self.control.resumed.wait(paused).unwrap();
         }
+    }
+}
+
+/// Groups blocks into chunks of about 100 000 transactions for the scanner
+///
+/// Blocks at or before the height of the sync state are skipped and every
+/// other block must connect to the previous one
+pub struct BlockChunker {
+    tx: SyncSender<Result<Vec<CompactBlock>>>,
+    chunk: Vec<CompactBlock>,
+    tx_count: usize,
+    start_height: u64,
+    start_hash: Hash,
+    prev_hash: Option<Vec<u8>>,
+}
 
-        let mut cmus: Vec<(super::Hash, bool)> = vec![];
-        for (b, db) in block_chunk.iter().zip(dec_block_chunk.iter()) {
-            // flush bridges or cmus (only one should exist)
-            if let Some(bridge) = bridges.take() {
-                // flush bridges
-                cmtree.add_bridge(&bridge);
+impl BlockChunker {
+    pub fn new(tx: SyncSender<Result<Vec<CompactBlock>>>, state: &SyncState) -> Self {
+        let start_height = state.height as u64;
+        let start_hash = state.block_hash;
+        let prev_hash = if start_height > 0 {
+            Some(start_hash.to_vec())
+        } else {
+            None
+        };
+        BlockChunker {
+            tx,
+            chunk: vec![],
+            tx_count: 0,
+            start_height,
+            start_hash,
+            prev_hash,
+        }
+    }
+
+    pub fn push(&mut self, cb: CompactBlock) -> Result<()> {
+        if cb.height <= self.start_height {
+            // already scanned
+            if cb.height == self.start_height && cb.hash != self.start_hash {
+                return Err(ReorgError {
+                    height: self.start_height as u32,
+                }
+                .into());
             }
-            if !cmus.is_empty() {
-                // flush nodes
-                cmtree.add_nodes(0, 0, &cmus);
-                cmus.clear();
+            return Ok(());
+        }
+        if let Some(prev_hash) = self.prev_hash.as_ref() {
+            if !cb.prev_hash.is_empty() && cb.prev_hash != *prev_hash {
+                return Err(ReorgError {
+                    height: cb.height as u32 - 1,
+                }
+                .into());
             }
-            assert_eq!(pos_start as usize, cmtree.pos);
-            assert!(bridges.is_none());
-            assert!(cmus.is_empty());
-
-            if db.notes.is_empty() {
-                // block has no new notes, use the block bridge
-                if let Some(bridge) = b.sapling_bridge.as_ref() {
-                    let bridge = Bridge::read(&*bridge.data, &SaplingHasher::default())?;
-                    cmtree.add_bridge(&bridge);
-                    pos_start += bridge.len as u32;
-                    assert_eq!(pos_start as usize, cmtree.pos);
+        }
+        self.prev_hash = Some(cb.hash.clone());
+        self.tx_count += cb.vtx.len();
+        self.chunk.push(cb);
+        if self.tx_count > 100_000 {
+            let blocks = std::mem::take(&mut self.chunk);
+            self.tx_count = 0;
+            self.tx.send(Ok(blocks))?;
+        }
+        Ok(())
+    }
+
+    /// Run the block producer `f`, then flush the last partial chunk
+    ///
+    /// An error from the producer is forwarded to the scanner
+    pub fn run<F: FnOnce(&mut Self) -> Result<()>>(mut self, f: F) {
+        let res = f(&mut self).and_then(|_| {
+            if !self.chunk.is_empty() {
+                let blocks = std::mem::take(&mut self.chunk);
+                self.tx.send(Ok(blocks))?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            let _ = self.tx.send(Err(e));
+        }
+    }
+}
+
+/// Load the state saved in `state_file`, or start after `birthday`
+/// if there is none
+fn load_state(state_file: Option<&str>, birthday: Option<&TreeState>) -> Result<SyncState> {
+    let state = match state_file {
+        Some(path) => SyncState::load(path)?,
+        None => None,
+    };
+    match (state, birthday) {
+        (Some(state), _) => Ok(state),
+        (None, Some(tree_state)) => SyncState::from_tree_state(tree_state),
+        (None, None) => Ok(SyncState::new()),
+    }
+}
+
+/// Scan the warp data file at `url` for all the `accounts` in a single pass
+///
+/// `url` is either the path of a local file, which is memory mapped,
+/// or a HTTP URL. The scan runs until the end of the data file.
+/// If `state_file` is given, the scan resumes from the state saved there
+/// and the state is updated after every chunk of blocks. Only the segments
+/// of the data file that follow the saved state are read.
+///
+/// Every block must connect to the previous one. Otherwise the saved state
+/// is rewound to a checkpoint before the fork and a [ReorgError] is returned
+/// so that the scan can be restarted from there
+///
+/// The scan blocks the calling thread, async callers should run it
+/// with `spawn_blocking`
+pub fn scan_accounts(
+    network: &Network,
+    url: &str,
+    accounts: &[Account],
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+) -> Result<ScanResult> {
+    let state = load_state(state_file, None)?;
+    let (source, header) = open_data_file(network, url, state.height + 1)?;
+    let end_height = Some(header.end_height);
+    let result = scan_source(
+        network, accounts, source, end_height, state, state_file, handle,
+    )?;
+    check_end_trees(&header, &result)?;
+    Ok(result)
+}
+
+/// Scan the warp data file at `url` from the birthday of the wallet
+///
+/// `birthday` has the commitment trees after the block before the birthday,
+/// as returned by lightwalletd. The blocks before the birthday are neither
+/// downloaded nor scanned. A state saved in `state_file` takes precedence
+pub fn scan_from_birthday(
+    network: &Network,
+    url: &str,
+    accounts: &[Account],
+    birthday: &TreeState,
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+) -> Result<ScanResult> {
+    let state = load_state(state_file, Some(birthday))?;
+    let (source, header) = open_data_file(network, url, state.height + 1)?;
+    let end_height = Some(header.end_height);
+    let result = scan_source(
+        network, accounts, source, end_height, state, state_file, handle,
+    )?;
+    check_end_trees(&header, &result)?;
+    Ok(result)
+}
+
+/// Scan the blocks from `start_height` to `end_height` (inclusive)
+/// of the warp data file at `url`
+///
+/// The sync state must be at the block before `start_height`, or empty
+/// if `start_height` is the first block of the data file
+pub fn scan_range(
+    network: &Network,
+    url: &str,
+    accounts: &[Account],
+    start_height: u32,
+    end_height: u32,
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+) -> Result<ScanResult> {
+    let state = load_state(state_file, None)?;
+    let (source, header) = open_data_file(network, url, start_height)?;
+    let next_height = if state.height > 0 {
+        state.height + 1
+    } else {
+        header.start_height
+    };
+    if start_height != next_height {
+        anyhow::bail!("Cannot scan from {start_height}, the next block to scan is {next_height}");
+    }
+    let result = scan_source(
+        network,
+        accounts,
+        source,
+        Some(end_height),
+        state,
+        state_file,
+        handle,
+    )?;
+    check_end_trees(&header, &result)?;
+    Ok(result)
+}
+
+/// Fail if the scan reached the end of the data file with other
+/// commitment trees than the ones in its header
+fn check_end_trees(header: &DataFileHeader, result: &ScanResult) -> Result<()> {
+    if result.height == header.end_height {
+        header
+            .sapling
+            .check(&result.sapling.tree)
+            .map_err(|e| anyhow::anyhow!("Sapling tree at the end of the data file: {e}"))?;
+        header
+            .orchard
+            .check(&result.orchard.tree)
+            .map_err(|e| anyhow::anyhow!("Orchard tree at the end of the data file: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Open the data file at `url` from the segment that contains `height`
+fn open_data_file(
+    network: &Network,
+    url: &str,
+    height: u32,
+) -> Result<(Box<dyn BlockSource>, DataFileHeader)> {
+    let (source, header): (Box<dyn BlockSource>, _) = if Path::new(url).is_file() {
+        let source = MmapSource::open_at(url, height)?;
+        let header = source.header().clone();
+        (Box::new(source), header)
+    } else {
+        let source = HttpSource::open_at(url, height)?;
+        let header = source.header().clone();
+        (Box::new(source), header)
+    };
+    header.check_network(network)?;
+    Ok((source, header))
+}
+
+/// Scan the blocks streamed by the lightwalletd server at `lwd_url`
+///
+/// The server replaces the transactions with more than `spam_threshold`
+/// inputs/outputs/actions by bridges, like in the warp data file.
+/// The scan starts after the saved state and stops at `end_height`,
+/// or at the tip of the chain if not given. The transactions of the
+/// transparent addresses of the accounts are scanned up to the same height
+pub async fn scan_lightwalletd(
+    network: &Network,
+    lwd_url: &str,
+    accounts: &[Account],
+    end_height: Option<u32>,
+    spam_threshold: u64,
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+) -> Result<ScanResult> {
+    let mut state = load_state(state_file, None)?;
+    let mut client = connect_lightwalletd(lwd_url).await?;
+    let end_height = match end_height {
+        Some(end_height) => end_height,
+        None => get_latest_height(&mut client).await?,
+    };
+    scan_transparent_txs(
+        network,
+        &mut LwdTransactionSource::new(client),
+        accounts,
+        &mut state.transparent,
+        end_height,
+    )
+    .await?;
+    let start_height = if state.height > 0 {
+        state.height + 1
+    } else {
+        u32::from(network.activation_height(NetworkUpgrade::Sapling).unwrap())
+    };
+    let source = LwdSource::new(lwd_url, start_height, Some(end_height), spam_threshold)?;
+    // the trial decryption does not yield to the runtime
+    let network = *network;
+    let accounts = accounts.to_vec();
+    let state_file = state_file.map(str::to_string);
+    let handle = handle.clone();
+    tokio::task::spawn_blocking(move || {
+        scan_source(
+            &network,
+            &accounts,
+            source,
+            Some(end_height),
+            state,
+            state_file.as_deref(),
+            &handle,
+        )
+    })
+    .await?
+}
+
+/// Scan the transactions of the transparent addresses of `accounts`
+/// with the lightwalletd server at `lwd_url`
+///
+/// The scan starts after the transparent state saved in `state_file` and stops
+/// at `end_height`, or at the tip of the chain if not given. The UTXOs are then
+/// part of the results of the shielded scans that use the same state file
+pub async fn scan_transparent(
+    network: &Network,
+    lwd_url: &str,
+    accounts: &[Account],
+    end_height: Option<u32>,
+    state_file: Option<&str>,
+) -> Result<TransparentResult> {
+    let mut state = load_state(state_file, None)?;
+    let mut client = connect_lightwalletd(lwd_url).await?;
+    let end_height = match end_height {
+        Some(end_height) => end_height,
+        None => get_latest_height(&mut client).await?,
+    };
+    scan_transparent_txs(
+        network,
+        &mut LwdTransactionSource::new(client),
+        accounts,
+        &mut state.transparent,
+        end_height,
+    )
+    .await?;
+    if let Some(path) = state_file {
+        state.save(path)?;
+    }
+    Ok(transparent_result(&mut state.transparent))
+}
+
+/// Scan the blocks of `source` that follow `state`, up to `end_height`
+/// if given
+///
+/// The blocks are read on a separate thread which stays at most
+/// a couple of chunks ahead of the trial decryption
+pub fn scan_source<S: BlockSource + 'static>(
+    network: &Network,
+    accounts: &[Account],
+    mut source: S,
+    end_height: Option<u32>,
+    mut state: SyncState,
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+) -> Result<ScanResult> {
+    if let Some(max_checkpoints) = handle.max_checkpoints {
+        state.set_max_checkpoints(max_checkpoints);
+    }
+    let (tx_blocks, rx_blocks) = sync_channel(2);
+    let chunker = BlockChunker::new(tx_blocks, &state);
+    let reader = std::thread::spawn(move || {
+        chunker.run(|chunker| {
+            while let Some(cb) = source.next_block()? {
+                if end_height.is_some_and(|end_height| cb.height > end_height as u64) {
+                    break;
                 }
-            } else {
-                for tx in b.vtx.iter() {
-                    if let Some(sapling_bridge) = tx.sapling_bridge.as_ref() {
-                        // tx was pruned
-                        if !cmus.is_empty() {
-                            // flush nodes
-                            cmtree.add_nodes(0, 0, &cmus);
-                            cmus.clear();
-                        }
+                chunker.push(cb)?;
+            }
+            Ok(())
+        })
+    });
 
-                        // accumulate bridge
-                        let bridge =
-                            Bridge::read(&*sapling_bridge.data, &SaplingHasher::default())?;
-                        pos_start += bridge.len as u32;
-                        bridges = match bridges.take() {
-                            Some(mut b) => {
-                                b.merge(&bridge, &cmtree.h);
-                                Some(b)
-                            }
-                            None => Some(bridge),
-                        };
-                    } else {
-                        if let Some(bridge) = bridges.take() {
-                            // flush bridges
-                            cmtree.add_bridge(&bridge);
-                        }
+    // the reader stops when the receiver is dropped by scan_chunks
+    let result = scan_chunks(
+        network, accounts, state, end_height, state_file, handle, rx_blocks,
+    )?;
+    // a cancelled scan does not wait for a read that may be blocked
+    // on the server, the reader stops once it returns
+    if !result.cancelled {
+        reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("The block reader panicked"))?;
+    }
+    Ok(result)
+}
 
-                        // accumulate cmus
-                        for o in tx.outputs.iter() {
-                            cmus.push((o.cmu.clone().try_into().unwrap(), false));
-                        }
-                        pos_start += tx.outputs.len() as u32;
-                        let cmus_pos_start = pos_start - cmus.len() as u32;
-                        while !notes.is_empty() {
-                            let n = &notes[0];
-                            if (n.0 - cmus_pos_start) as usize >= cmus.len() {
-                                break;
-                            }
-                            cmus[(n.0 - cmus_pos_start) as usize].1 = true;
-                            notes.remove(0);
-                        }
+/// Trial decrypt the chunks of blocks received from `rx_blocks` and
+/// update the sync state
+fn scan_chunks(
+    network: &Network,
+    accounts: &[Account],
+    mut state: SyncState,
+    end_height: Option<u32>,
+    state_file: Option<&str>,
+    handle: &ScanHandle,
+    rx_blocks: Receiver<Result<Vec<CompactBlock>>>,
+) -> Result<ScanResult> {
+    let ivks = PreparedIvks::new(accounts);
+    let mut progress = ProgressTracker::new(end_height);
+    let mut sapling_pos = state.sapling.tree.pos as u32;
+    let mut orchard_pos = state.orchard.tree.pos as u32;
+
+    let start_time = Instant::now();
+
+    let mut cancelled = false;
+    'scan: loop {
+        // between chunks, after the state is saved
+        handle.wait_if_paused();
+        // poll so that a cancel does not wait for a blocked reader
+        let block_chunk = loop {
+            if handle.is_cancelled() {
+                log::info!("Scan cancelled at {}", state.height);
+                cancelled = true;
+                break 'scan;
+            }
+            match rx_blocks.recv_timeout(Duration::from_millis(100)) {
+                Ok(block_chunk) => break block_chunk,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break 'scan,
+            }
+        };
+        let block_chunk = match block_chunk {
+            Ok(block_chunk) => block_chunk,
+            Err(e) => {
+                if let Some(reorg) = e.downcast_ref::<ReorgError>() {
+                    let height = state.rewind(reorg.height - 1)?;
+                    log::warn!("{reorg}, rewound to {height}");
+                    if let Some(path) = state_file {
+                        state.save(path)?;
                     }
                 }
+                return Err(e);
             }
+        };
+        let dec_block_chunk: Vec<_> = block_chunk
+            .par_iter()
+            .map(|b| decrypt_block(network, b, &ivks))
+            .collect::<Result<_>>()?;
+
+        let note_count: usize = dec_block_chunk
+            .iter()
+            .map(|db| db.sapling_notes.len() + db.orchard_notes.len())
+            .sum();
+        let mut sapling_notes = vec![];
+        let mut orchard_notes = vec![];
+        let sapling_pos_start = sapling_pos;
+        let orchard_pos_start = orchard_pos;
+        for db in dec_block_chunk.iter() {
+            for (i, k, note, txid, index) in db.sapling_notes.iter() {
+                let p = sapling_pos + i;
+                let (account, scope, nk) = &ivks.sapling.keys[*k];
+                let nf = note.nf(nk, p as u64);
+                log::info!(
+                    "Received {} at {} for {account}",
+                    note.value().inner(),
+                    db.height
+                );
+                let note = ReceivedNote {
+                    txid: *txid,
+                    output_index: *index,
+                    ..ReceivedNote::from_sapling(*account, *scope, db.height, p, note, nf.0)
+                };
+                state.sapling.nfs.insert(nf.0, note);
+                sapling_notes.push(p);
+            }
+            for (i, k, note, txid, index) in db.orchard_notes.iter() {
+                let p = orchard_pos + i;
+                let (account, scope, fvk) = &ivks.orchard.keys[*k];
+                let nf = note.nullifier(fvk).to_bytes();
+                log::info!(
+                    "Received {} at {} for {account}",
+                    note.value().inner(),
+                    db.height
+                );
+                let note = ReceivedNote {
+                    txid: *txid,
+                    output_index: *index,
+                    ..ReceivedNote::from_orchard(*account, *scope, db.height, p, note, nf)
+                };
+                state.orchard.nfs.insert(nf, note);
+                orchard_notes.push(p);
+            }
+            sapling_pos += db.count_outputs;
+            orchard_pos += db.count_actions;
         }
 
+        let has_sapling_notes: Vec<_> = dec_block_chunk
+            .iter()
+            .map(|db| !db.sapling_notes.is_empty())
+            .collect();
+        update_tree(
+            Pool::Sapling,
+            &mut state.sapling.tree,
+            &block_chunk,
+            &has_sapling_notes,
+            sapling_pos_start,
+            sapling_notes,
+            state.max_checkpoints,
+        )?;
+        let has_orchard_notes: Vec<_> = dec_block_chunk
+            .iter()
+            .map(|db| !db.orchard_notes.is_empty())
+            .collect();
+        update_tree(
+            Pool::Orchard,
+            &mut state.orchard.tree,
+            &block_chunk,
+            &has_orchard_notes,
+            orchard_pos_start,
+            orchard_notes,
+            state.max_checkpoints,
+        )?;
+
+        let spend_count = detect_spends(Pool::Sapling, &mut state.sapling, &block_chunk)
+            + detect_spends(Pool::Orchard, &mut state.orchard, &block_chunk);
+
+        state.checkpoint_blocks(&block_chunk)?;
+        if let Some(path) = state_file {
+            state.save(path)?;
+        }
+        handle.report(progress.add_chunk(&block_chunk, note_count, spend_count));
+    }
+    let duration = start_time.elapsed();
+    log::info!("Time elapsed in full scan is: {:?}", duration);
+
+    let sapling = pool_result(&mut state.sapling)?;
+    let orchard = pool_result(&mut state.orchard)?;
+    let transparent = transparent_result(&mut state.transparent);
+    Ok(ScanResult {
+        height: state.height,
+        balance: sapling.balance() + orchard.balance() + transparent.balance(),
+        sapling,
+        orchard,
+        transparent,
+        cancelled,
+    })
+}
+
+/// Append the note commitments of `blocks` to the tree of `pool`
+///
+/// `pos` is the position of the first commitment of the chunk and
+/// `notes` are the positions of the notes received in the chunk, in order.
+/// A witness is created for each of them. The tree is checkpointed at the
+/// last `max_checkpoints` blocks, like [SyncState::checkpoint_blocks]
+fn update_tree<H: Hasher<D = Hash>>(
+    pool: Pool,
+    tree: &mut MerkleTree<H>,
+    blocks: &[CompactBlock],
+    has_notes: &[bool],
+    mut pos: u32,
+    mut notes: Vec<u32>,
+    max_checkpoints: usize,
+) -> Result<()> {
+    let checkpoint_from = blocks.last().map_or(0, |b| {
+        (b.height as u32 + 1).saturating_sub(max_checkpoints as u32)
+    });
+    let mut bridges: Option<Bridge<H>> = None;
+    let mut cmus: Vec<(Hash, bool)> = vec![];
+    let mut prev_height = None;
+    for (b, &block_has_notes) in blocks.iter().zip(has_notes.iter()) {
         // flush bridges or cmus (only one should exist)
         if let Some(bridge) = bridges.take() {
             // flush bridges
-            cmtree.add_bridge(&bridge);
+            tree.add_bridge(&bridge);
         }
         if !cmus.is_empty() {
             // flush nodes
-            cmtree.add_nodes(0, 0, &cmus);
+            tree.add_nodes(0, 0, &cmus);
             cmus.clear();
         }
-        assert_eq!(pos_start as usize, cmtree.pos);
+        check_position(pool, pos, tree)?;
+        assert!(bridges.is_none());
+        assert!(cmus.is_empty());
+        // the tree is now at the end of the previous block
+        if let Some(height) = prev_height {
+            if height >= checkpoint_from {
+                tree.checkpoint(height, max_checkpoints);
+            }
+        }
+        prev_height = Some(b.height as u32);
 
-        // detect spends
-        for b in block_chunk.iter() {
+        let block_bridge = if block_has_notes {
+            None
+        } else {
+            pool.block_bridge(b)
+        };
+        if let Some(bridge) = block_bridge {
+            // block has no new notes, use the block bridge
+            let bridge = Bridge::read(&*bridge.data, &tree.h)?;
+            tree.add_bridge(&bridge);
+            pos += bridge.len as u32;
+            check_position(pool, pos, tree)?;
+        } else {
+            // the block has new notes or no block bridge,
+            // add the commitments and bridges of its transactions
             for tx in b.vtx.iter() {
-                for s in tx.spends.iter() {
-                    if nfs.contains_key(&*s.nf) {
-                        let (p, nv) = nfs[&*s.nf];
-                        nfs.remove(&*s.nf);
-                        cmtree.remove_witness(p as usize);
-                        println!("Spent {nv}");
-                        balance -= nv as i64;
+                if let Some(tx_bridge) = pool.tx_bridge(tx) {
+                    // tx was pruned
+                    if !cmus.is_empty() {
+                        // flush nodes
+                        tree.add_nodes(0, 0, &cmus);
+                        cmus.clear();
+                    }
+
+                    // accumulate bridge
+                    let bridge = Bridge::read(&*tx_bridge.data, &tree.h)?;
+                    pos += bridge.len as u32;
+                    bridges = match bridges.take() {
+                        Some(mut b) => {
+                            b.merge(&bridge, &tree.h);
+                            Some(b)
+                        }
+                        None => Some(bridge),
+                    };
+                } else {
+                    if let Some(bridge) = bridges.take() {
+                        // flush bridges
+                        tree.add_bridge(&bridge);
+                    }
+
+                    // accumulate cmus
+                    let tx_cmus = pool.tx_commitments(tx)?;
+                    check_nodes(&tree.h, &tx_cmus)?;
+                    pos += tx_cmus.len() as u32;
+                    cmus.extend(tx_cmus.into_iter().map(|cmu| (cmu, false)));
+                    let cmus_pos_start = pos - cmus.len() as u32;
+                    while !notes.is_empty() {
+                        let n = notes[0];
+                        if (n - cmus_pos_start) as usize >= cmus.len() {
+                            break;
+                        }
+                        cmus[(n - cmus_pos_start) as usize].1 = true;
+                        notes.remove(0);
                     }
                 }
             }
         }
     }
 
-    println!("Final height = {final_height}");
-    let duration = start_time.elapsed();
-    println!("Time elapsed in sapling full scan is: {:?}", duration);
+    // flush bridges or cmus (only one should exist)
+    if let Some(bridge) = bridges.take() {
+        // flush bridges
+        tree.add_bridge(&bridge);
+    }
+    if !cmus.is_empty() {
+        // flush nodes
+        tree.add_nodes(0, 0, &cmus);
+        cmus.clear();
+    }
+    check_position(pool, pos, tree)?;
+    if let Some(height) = prev_height {
+        if height >= checkpoint_from {
+            tree.checkpoint(height, max_checkpoints);
+        }
+    }
+    Ok(())
+}
 
-    let er = super::empty_roots(&cmtree.h);
-    let edge = cmtree.edge(&er);
-    for w in cmtree.witnesses.iter() {
-        let (root, _proof) = w.root(&er, &edge, &cmtree.h);
-        println!("{} {}", w.path.pos, hex::encode(&root));
-        // this is the anchor at the end height of the compact file
-        assert_eq!(&root, &*hex::decode("44d4dce2ed4a15a775423e92802615cc5c3e4168f2dd2ca65ac2dd8d853bb523").unwrap());
+/// Fail if the tree is not at the position `pos` computed from the blocks,
+/// which happens when the length of a bridge is wrong
+fn check_position<H: Hasher>(pool: Pool, pos: u32, tree: &MerkleTree<H>) -> Result<()> {
+    if pos as usize != tree.pos {
+        anyhow::bail!(
+            "The {pool:?} tree is at position {} instead of {pos}, invalid bridge length",
+            tree.pos
+        );
     }
+    Ok(())
+}
 
-    for (i, (p, v)) in nfs.values().enumerate() {
-        println!("Note #{i} / {p} = {v}");
+/// Mark the notes whose nullifiers appear in `blocks` as spent
+/// and return how many were
+fn detect_spends<H: Hasher>(
+    pool: Pool,
+    state: &mut PoolState<H>,
+    blocks: &[CompactBlock],
+) -> usize {
+    let mut count = 0;
+    for b in blocks.iter() {
+        for tx in b.vtx.iter() {
+            for nf in pool.tx_nullifiers(tx) {
+                if let Some(note) = state.nfs.remove(nf) {
+                    state
+                        .tree
+                        .remove_witness(note.position as usize, b.height as u32);
+                    log::info!("Spent {} at {}", note.value, b.height);
+                    count += 1;
+                    state.spent_notes.push(SpentNote {
+                        note,
+                        spent_height: b.height as u32,
+                        spent_txid: tx.hash.clone().try_into().unwrap_or_default(),
+                    });
+                }
+            }
+        }
+    }
+    count
+}
+
+/// Compute the anchor and move the unspent notes and the tree
+/// out of the pool state
+fn pool_result<H: Hasher<D = Hash>>(state: &mut PoolState<H>) -> Result<PoolResult<H>> {
+    let tree = std::mem::replace(&mut state.tree, MerkleTree::empty(H::default()));
+    let er = super::empty_roots(&tree.h);
+    let edge = tree.edge(&er);
+    // the root of the tree is the top of the edge
+    let anchor = edge[DEPTH - 1];
+    let mut notes = vec![];
+    for w in tree.witnesses.iter() {
+        let (root, _proof) = w.root(&er, &edge, &tree.h);
+        if root != anchor {
+            anyhow::bail!(
+                "Witness at position {} does not match the anchor",
+                w.path.pos
+            );
+        }
+        let note = state
+            .nfs
+            .values()
+            .find(|n| n.position as usize == w.path.pos)
+            .ok_or(anyhow::anyhow!("No note at position {}", w.path.pos))?
+            .clone();
+        notes.push(UnspentNote {
+            note,
+            witness: w.clone(),
+        });
     }
-    println!("Balance = {balance}");
 
-    // let mut client = connect_lightwalletd(url).await?;
-    // let rep = client.get_tree_state(Request::new(BlockId { height: height as u64, hash: vec![] })).await?.into_inner();
-    // let tree = hex::decode(&rep.sapling_tree).unwrap();
-    // let tree = zcash_primitives::merkle_tree::CommitmentTree::<Node>::read(&*tree)?;
-    // let root = tree.root();
-    // println!("server root {}", hex::encode(&root.repr));
+    Ok(PoolResult {
+        anchor,
+        edge,
+        notes,
+        spent_notes: std::mem::take(&mut state.spent_notes),
+        sent_outputs: vec![],
+        tree,
+    })
+}
 
-    Ok(balance as u64)
+/// Move the UTXOs and spent outputs out of the transparent state
+fn transparent_result(state: &mut TransparentState) -> TransparentResult {
+    let mut utxos: Vec<_> = std::mem::take(&mut state.utxos).into_values().collect();
+    utxos.sort_by_key(|u| (u.height, u.txid, u.vout));
+    TransparentResult {
+        height: state.height,
+        utxos,
+        spent_utxos: std::mem::take(&mut state.spent_utxos),
+    }
 }
 
 struct EncryptedOutput<P> {
@@ -220,18 +1032,18 @@ struct EncryptedOutput<P> {
     _phantom: PhantomData<P>,
 }
 
-impl <P> EncryptedOutput<P> {
-    pub fn new(co: crate::lw_rpc::CompactSaplingOutput) -> Self {
-        Self {
-            epk: co.epk.try_into().unwrap(),
-            cmu: co.cmu.try_into().unwrap(),
-            enc: co.ciphertext.try_into().unwrap(),
-            _phantom: PhantomData::default(),
-        }
+impl<P> EncryptedOutput<P> {
+    pub fn new(co: &crate::lw_rpc::CompactSaplingOutput) -> Result<Self> {
+        Ok(Self {
+            epk: to_array(&co.epk, "sapling epk")?,
+            cmu: to_array(&co.cmu, "sapling cmu")?,
+            enc: to_array(&co.ciphertext, "sapling ciphertext")?,
+            _phantom: PhantomData,
+        })
     }
 }
 
-impl <P: Parameters> ShieldedOutput<SaplingDomain<P>, 52> for EncryptedOutput<P> {
+impl<P: Parameters> ShieldedOutput<SaplingDomain<P>, 52> for EncryptedOutput<P> {
     fn ephemeral_key(&self) -> EphemeralKeyBytes {
         EphemeralKeyBytes::from(self.epk)
     }
@@ -245,40 +1057,576 @@ impl <P: Parameters> ShieldedOutput<SaplingDomain<P>, 52> for EncryptedOutput<P>
     }
 }
 
+/// Fixed size field of a compact block, rejected if it has the wrong length
+fn to_array<const N: usize>(bytes: &[u8], name: &str) -> Result<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid {name} length {}", bytes.len()))
+}
+
+fn compact_action(a: &CompactOrchardAction) -> Result<CompactAction> {
+    let nf: [u8; 32] = to_array(&a.nullifier, "orchard nullifier")?;
+    let cmx: [u8; 32] = to_array(&a.cmx, "orchard cmx")?;
+    let nf = Option::from(Nullifier::from_bytes(&nf))
+        .ok_or(anyhow::anyhow!("Invalid orchard nullifier"))?;
+    let cmx = Option::from(ExtractedNoteCommitment::from_bytes(&cmx))
+        .ok_or(anyhow::anyhow!("Invalid orchard cmx"))?;
+    let epk: [u8; 32] = to_array(&a.ephemeral_key, "orchard epk")?;
+    let enc: [u8; 52] = to_array(&a.ciphertext, "orchard ciphertext")?;
+    Ok(CompactAction::from_parts(
+        nf,
+        cmx,
+        EphemeralKeyBytes(epk),
+        enc,
+    ))
+}
+
+/// Incoming viewing keys of a pool for every account and scope,
+/// with the key used to derive the nullifiers of their notes
+struct PoolIvks<IVK, NK> {
+    ivks: Vec<IVK>,
+    keys: Vec<(u32, Scope, NK)>,
+}
+
+impl<IVK, NK> PoolIvks<IVK, NK> {
+    fn new() -> Self {
+        PoolIvks {
+            ivks: vec![],
+            keys: vec![],
+        }
+    }
+
+    fn add(&mut self, account: u32, scope: Scope, ivk: IVK, nk: NK) {
+        self.ivks.push(ivk);
+        self.keys.push((account, scope, nk));
+    }
+}
+
+pub(super) struct PreparedIvks {
+    sapling: PoolIvks<PreparedIncomingViewingKey, NullifierDerivingKey>,
+    orchard: PoolIvks<PreparedOrchardIvk, orchard::keys::FullViewingKey>,
+}
+
+impl PreparedIvks {
+    pub(super) fn new(accounts: &[Account]) -> Self {
+        let mut sapling = PoolIvks::new();
+        let mut orchard = PoolIvks::new();
+        for a in accounts.iter() {
+            if let Some(fvk) = a.keys.sapling.as_ref() {
+                // change notes are sent to the ZIP-32 internal address
+                // and have their own nullifier deriving key
+                for scope in [Scope::External, Scope::Internal] {
+                    let ivk = PreparedIncomingViewingKey::new(&fvk.to_ivk(scope));
+                    sapling.add(a.id, scope, ivk, fvk.to_nk(scope));
+                }
+            }
+            if let Some(fvk) = a.keys.orchard.as_ref() {
+                for (scope, orchard_scope) in [
+                    (Scope::External, orchard::keys::Scope::External),
+                    (Scope::Internal, orchard::keys::Scope::Internal),
+                ] {
+                    let ivk = PreparedOrchardIvk::new(&fvk.to_ivk(orchard_scope));
+                    orchard.add(a.id, scope, ivk, fvk.clone());
+                }
+            }
+        }
+        PreparedIvks { sapling, orchard }
+    }
+}
+
+/// Trial decrypt the mempool transactions `txs`, which would be mined at `height`
+pub(super) fn decrypt_mempool_txs(
+    network: &Network,
+    height: u32,
+    txs: Vec<CompactTx>,
+    ivks: &PreparedIvks,
+) -> Result<Vec<PendingNote>> {
+    let block = CompactBlock {
+        height: height as u64,
+        vtx: txs,
+        ..CompactBlock::default()
+    };
+    let db = decrypt_block(network, &block, ivks)?;
+    let sapling_notes = db.sapling_notes.iter().map(|(_, k, note, txid, index)| {
+        let (account, scope, _) = &ivks.sapling.keys[*k];
+        PendingNote {
+            pool: Pool::Sapling,
+            account: *account,
+            scope: *scope,
+            value: note.value().inner(),
+            txid: *txid,
+            output_index: *index,
+        }
+    });
+    let orchard_notes = db.orchard_notes.iter().map(|(_, k, note, txid, index)| {
+        let (account, scope, _) = &ivks.orchard.keys[*k];
+        PendingNote {
+            pool: Pool::Orchard,
+            account: *account,
+            scope: *scope,
+            value: note.value().inner(),
+            txid: *txid,
+            output_index: *index,
+        }
+    });
+    Ok(sapling_notes.chain(orchard_notes).collect())
+}
+
+/// Notes are (position in block, index of the ivk, note)
 struct DecBlock {
+    height: u32,
     count_outputs: u32,
-    notes: Vec<(u32, Note)>,
+    /// Position in the block, key index, note and its txid and output index
+    sapling_notes: Vec<(u32, usize, Note, Hash, u32)>,
+    count_actions: u32,
+    orchard_notes: Vec<(u32, usize, orchard::Note, Hash, u32)>,
 }
 
-fn decrypt_block(
-    network: &Network,
-    block: &crate::lw_rpc::CompactBlock,
-    ivk: &PreparedIncomingViewingKey,
-) -> Result<DecBlock> {
+fn decrypt_block(network: &Network, block: &CompactBlock, ivks: &PreparedIvks) -> Result<DecBlock> {
     let mut outputs = vec![];
+    let mut actions = vec![];
+    // positions in the block, which skip over the bridged outputs/actions
+    let mut output_positions = vec![];
+    let mut action_positions = vec![];
     let mut pos = 0u32;
+    let mut orchard_pos = 0u32;
     for tx in block.vtx.iter() {
-        for o in tx.outputs.iter() {
+        // all zeros if the source does not have the txids
+        let txid: Hash = tx.hash.clone().try_into().unwrap_or_default();
+        for (i, o) in tx.outputs.iter().enumerate() {
             let d = SaplingDomain::for_height(*network, BlockHeight::from_u32(block.height as u32));
-            outputs.push((d, EncryptedOutput::new(o.clone())));
+            outputs.push((d, EncryptedOutput::new(o)?));
+            output_positions.push((pos, txid, i as u32));
             pos += 1;
         }
         if let Some(sapling_bridge) = tx.sapling_bridge.as_ref() {
             pos += sapling_bridge.len;
         }
+        for (i, a) in trial_actions(tx).iter().enumerate() {
+            let action = compact_action(a)?;
+            actions.push((OrchardDomain::for_nullifier(action.nullifier()), action));
+            action_positions.push((orchard_pos, txid, i as u32));
+            orchard_pos += 1;
+        }
+        if let Some(orchard_bridge) = tx.orchard_bridge.as_ref() {
+            orchard_pos += orchard_bridge.len;
+        }
     }
-    let decrypted =
-        try_compact_note_decryption::<SaplingDomain<_>, EncryptedOutput<Network>>(slice::from_ref(ivk), &outputs);
-    let mut notes = vec![];
-    for (pos, dec) in decrypted.iter().enumerate() {
-        if let Some(((note, _), _)) = dec {
-            println!("Received {}", note.value().inner());
-            notes.push((pos as u32, note.clone()));
+    let decrypted = try_compact_note_decryption::<SaplingDomain<_>, EncryptedOutput<Network>>(
+        &ivks.sapling.ivks,
+        &outputs,
+    );
+    let mut sapling_notes = vec![];
+    for (i, dec) in decrypted.iter().enumerate() {
+        if let Some(((note, _), k)) = dec {
+            let (p, txid, index) = output_positions[i];
+            sapling_notes.push((p, *k, note.clone(), txid, index));
+        }
+    }
+    let decrypted = try_compact_note_decryption(&ivks.orchard.ivks, &actions);
+    let mut orchard_notes = vec![];
+    for (i, dec) in decrypted.iter().enumerate() {
+        if let Some(((note, _), k)) = dec {
+            let (p, txid, index) = action_positions[i];
+            orchard_notes.push((p, *k, *note, txid, index));
         }
     }
     let block = DecBlock {
+        height: block.height as u32,
         count_outputs: pos,
-        notes,
+        sapling_notes,
+        count_actions: orchard_pos,
+        orchard_notes,
     };
     Ok(block)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::source::MemorySource;
+    use super::super::testing::{
+        blocks, leaf, orchard_action, sapling_output, sapling_output_to, PHRASE, START_HEIGHT,
+    };
+    use super::*;
+    use crate::lw_rpc::CompactSaplingSpend;
+    use orchard::tree::MerkleHashOrchard;
+    use zcash_primitives::merkle_tree::CommitmentTree;
+    use zcash_primitives::sapling::Node;
+
+    fn tree_hex<N: Hashable>(cmus: &[Vec<u8>]) -> String {
+        let mut tree = CommitmentTree::<N>::empty();
+        for cmu in cmus {
+            tree.append(N::read(&cmu[..]).unwrap()).unwrap();
+        }
+        let mut data = vec![];
+        tree.write(&mut data).unwrap();
+        hex::encode(data)
+    }
+
+    /// Tree state after the last of `blocks`, as returned by lightwalletd
+    fn tree_state(blocks: &[CompactBlock]) -> TreeState {
+        let block = blocks.last().unwrap();
+        let txs = blocks.iter().flat_map(|b| b.vtx.iter());
+        let cmus: Vec<_> = txs
+            .clone()
+            .flat_map(|tx| tx.outputs.iter().map(|o| o.cmu.clone()))
+            .collect();
+        let cmxs: Vec<_> = txs
+            .flat_map(|tx| tx.actions.iter().map(|a| a.cmx.clone()))
+            .collect();
+        let mut hash = block.hash.clone();
+        hash.reverse();
+        TreeState {
+            height: block.height,
+            hash: hex::encode(hash),
+            sapling_tree: tree_hex::<Node>(&cmus),
+            orchard_tree: tree_hex::<MerkleHashOrchard>(&cmxs),
+            ..TreeState::default()
+        }
+    }
+
+    fn scan(accounts: &[Account], blocks: Vec<CompactBlock>, state: SyncState) -> ScanResult {
+        scan_source(
+            &Network::MainNetwork,
+            accounts,
+            MemorySource::new(blocks),
+            None,
+            state,
+            None,
+            &ScanHandle::default(),
+        )
+        .unwrap()
+    }
+
+    /// Merkle paths of the notes received after `height`
+    fn paths<H: Hasher<D = Hash>>(
+        result: &PoolResult<H>,
+        height: u32,
+    ) -> Vec<(u32, [Hash; DEPTH])> {
+        let h = H::default();
+        let er = super::super::empty_roots(&h);
+        result
+            .notes
+            .iter()
+            .filter(|n| n.note.height > height)
+            .map(|n| {
+                let (root, path) = n.witness.root(&er, &result.edge, &h);
+                assert_eq!(root, result.anchor);
+                (n.note.position, path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scan_from_tree_state_matches_full_scan() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let accounts = [account.clone()];
+        let blocks = blocks(&account, 30);
+        let full = scan(&accounts, blocks.clone(), SyncState::new());
+        assert_eq!(full.sapling.notes.len(), 6);
+
+        for k in [1, 7, 12, 17, 29] {
+            let birthday = tree_state(&blocks[..k]);
+            let state = SyncState::from_tree_state(&birthday).unwrap();
+            let tail = scan(&accounts, blocks[k..].to_vec(), state);
+            let height = START_HEIGHT + k as u32;
+            assert_eq!(tail.height, full.height);
+            assert_eq!(tail.sapling.anchor, full.sapling.anchor);
+            assert_eq!(tail.orchard.anchor, full.orchard.anchor);
+            assert_eq!(tail.sapling.edge, full.sapling.edge);
+            assert_eq!(tail.sapling.notes.len(), paths(&full.sapling, height).len());
+            assert_eq!(paths(&tail.sapling, height), paths(&full.sapling, height));
+        }
+    }
+
+    /// Root of a tree in the hex format of `TreeState`
+    fn root<N: Hashable>(tree: &str) -> Hash {
+        let tree = CommitmentTree::<N>::read(&*hex::decode(tree).unwrap()).unwrap();
+        let mut root = [0u8; 32];
+        tree.root().write(&mut root[..]).unwrap();
+        root
+    }
+
+    #[test]
+    fn result_has_the_anchors_of_the_last_blocks() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let blocks = blocks(&account, 30);
+        let result = scan(&[account], blocks.clone(), SyncState::new());
+        let tree = &result.sapling.tree;
+        for k in [1, 7, 12, 29, 30] {
+            let height = START_HEIGHT + k as u32;
+            let tree_state = tree_state(&blocks[..k]);
+            let anchor = tree.anchor_at(height).unwrap();
+            assert_eq!(anchor, root::<Node>(&tree_state.sapling_tree));
+            let orchard_anchor = result.orchard.tree.anchor_at(height).unwrap();
+            assert_eq!(
+                orchard_anchor,
+                root::<MerkleHashOrchard>(&tree_state.orchard_tree)
+            );
+            for n in result.sapling.notes.iter() {
+                let witness = tree.witness_root_at(n.note.position as usize, height);
+                if n.note.height <= height {
+                    assert_eq!(witness.unwrap().0, anchor);
+                } else {
+                    assert!(witness.is_err());
+                }
+            }
+        }
+        assert_eq!(
+            tree.anchor_at(START_HEIGHT + 30).unwrap(),
+            result.sapling.anchor
+        );
+    }
+
+    #[test]
+    fn handle_sets_the_checkpoint_window() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let blocks = blocks(&account, 30);
+        let result = scan_source(
+            &Network::MainNetwork,
+            &[account],
+            MemorySource::new(blocks),
+            None,
+            SyncState::new(),
+            None,
+            &ScanHandle::default().with_max_checkpoints(5),
+        )
+        .unwrap();
+        for tree_checkpoints in [
+            result.sapling.tree.checkpoints.len(),
+            result.orchard.tree.checkpoints.len(),
+        ] {
+            assert_eq!(tree_checkpoints, 5);
+        }
+        assert!(result.sapling.tree.anchor_at(START_HEIGHT + 26).is_ok());
+        assert!(result.sapling.tree.anchor_at(START_HEIGHT + 25).is_err());
+    }
+
+    #[test]
+    fn non_canonical_bridge_node_is_an_error() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let mut blocks = blocks(&account, 3);
+        let h = OrchardHasher::default();
+        let mut bridge = Bridge::empty(&h);
+        bridge.len = 1;
+        bridge.leaf = Some([0xFF; 32]);
+        let mut data = vec![];
+        bridge.write(&mut data, &h).unwrap();
+        blocks[0].orchard_bridge = Some(crate::lw_rpc::Bridge { len: 1, data });
+        let result = scan_source(
+            &Network::MainNetwork,
+            &[account],
+            MemorySource::new(blocks),
+            None,
+            SyncState::new(),
+            None,
+            &ScanHandle::default(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn orchard_notes_are_received_and_spent() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let mut blocks = blocks(&account, 10);
+        blocks[3].vtx[0]
+            .actions
+            .push(orchard_action(&account, 5000, leaf(1000)));
+        let accounts = [account];
+        let result = scan(&accounts, blocks.clone(), SyncState::new());
+        assert_eq!(result.orchard.notes.len(), 1);
+        let note = &result.orchard.notes[0].note;
+        assert_eq!(note.value, 5000);
+        assert_eq!(note.account, 0);
+        assert_eq!(note.scope, Scope::External);
+        assert_eq!(note.height, START_HEIGHT + 4);
+        assert_eq!(note.rho, leaf(1000));
+        assert_eq!(result.orchard.balance(), 5000);
+        assert_eq!(result.balance, result.sapling.balance() + 5000);
+        let h = OrchardHasher::default();
+        let er = super::super::empty_roots(&h);
+        let (root, _) = result.orchard.notes[0]
+            .witness
+            .root(&er, &result.orchard.edge, &h);
+        assert_eq!(root, result.orchard.anchor);
+
+        // an action with the nullifier of the note spends it
+        let nf = note.nf;
+        blocks[8].vtx[0].actions.push(CompactOrchardAction {
+            nullifier: nf.to_vec(),
+            ..orchard_action(&accounts[0], 10, leaf(1001))
+        });
+        let result = scan(&accounts, blocks, SyncState::new());
+        assert!(result.orchard.notes.is_empty());
+        assert_eq!(result.orchard.spent_notes.len(), 1);
+        assert_eq!(result.orchard.spent_notes[0].note.nf, nf);
+        assert_eq!(result.orchard.balance(), 0);
+    }
+
+    #[test]
+    fn notes_are_attributed_to_their_account() {
+        let account0 = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let account1 = Account::from_seed(&Network::MainNetwork, 1, PHRASE).unwrap();
+        let mut blocks = blocks(&account0, 10);
+        blocks[4].vtx[0]
+            .outputs
+            .push(sapling_output(&account1, 7000, 42));
+        blocks[5].vtx[0]
+            .actions
+            .push(orchard_action(&account1, 3000, leaf(1000)));
+        let accounts = [account0, account1];
+        let result = scan(&accounts, blocks.clone(), SyncState::new());
+
+        // the notes of account 0 are in the blocks 2 and 7
+        assert_eq!(result.account_balance(0), 2000 + 7000);
+        assert_eq!(result.account_balance(1), 7000 + 3000);
+        assert_eq!(result.balance, 2000 + 7000 + 7000 + 3000);
+        let sapling1: Vec<_> = result
+            .sapling
+            .notes
+            .iter()
+            .filter(|n| n.note.account == 1)
+            .map(|n| (n.note.height, n.note.value))
+            .collect();
+        assert_eq!(sapling1, [(START_HEIGHT + 5, 7000)]);
+        assert_eq!(result.orchard.notes.len(), 1);
+        assert_eq!(result.orchard.notes[0].note.account, 1);
+
+        // the same notes as a scan of the account alone
+        let alone = scan(&accounts[1..], blocks, SyncState::new());
+        assert_eq!(alone.sapling.notes.len(), 1);
+        let position = |r: &ScanResult| {
+            let n = r.sapling.notes.iter().find(|n| n.note.account == 1);
+            n.unwrap().note.position
+        };
+        assert_eq!(position(&alone), position(&result));
+        assert_eq!(alone.sapling.anchor, result.sapling.anchor);
+    }
+
+    #[test]
+    fn change_notes_have_the_internal_scope() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let (_, change_address) = account.keys.sapling.as_ref().unwrap().change_address();
+        let mut blocks = blocks(&account, 10);
+        blocks[3].vtx[0]
+            .outputs
+            .push(sapling_output_to(&change_address, 4000, 42));
+        let accounts = [account];
+        let result = scan(&accounts, blocks.clone(), SyncState::new());
+        let scopes: Vec<_> = result
+            .sapling
+            .notes
+            .iter()
+            .map(|n| (n.note.value, n.note.scope))
+            .collect();
+        assert_eq!(
+            scopes,
+            [
+                (2000, Scope::External),
+                (4000, Scope::Internal),
+                (7000, Scope::External)
+            ]
+        );
+        assert_eq!(result.account_balance(0), 2000 + 4000 + 7000);
+
+        // the nullifier of a change note is derived with the internal key
+        let change = &result.sapling.notes[1].note;
+        let nf = change.nf;
+        blocks[8].vtx[0]
+            .spends
+            .push(CompactSaplingSpend { nf: nf.to_vec() });
+        let result = scan(&accounts, blocks, SyncState::new());
+        assert_eq!(result.sapling.spent_notes.len(), 1);
+        assert_eq!(result.sapling.spent_notes[0].note.nf, nf);
+        assert_eq!(result.account_balance(0), 2000 + 7000);
+    }
+
+    /// Blocks sent by the test, a read blocks until the next one
+    struct ChannelSource(Receiver<CompactBlock>);
+
+    impl BlockSource for ChannelSource {
+        fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+            Ok(self.0.recv().ok())
+        }
+    }
+
+    #[test]
+    fn cancel_does_not_wait_for_a_blocked_read() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        for b in blocks(&account, 5) {
+            tx.send(b).unwrap();
+        }
+        let handle = ScanHandle::default();
+        let canceller = handle.clone();
+        let cancel = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            canceller.cancel();
+        });
+        let result = scan_source(
+            &Network::MainNetwork,
+            &[account],
+            ChannelSource(rx),
+            None,
+            SyncState::new(),
+            None,
+            &handle,
+        )
+        .unwrap();
+        cancel.join().unwrap();
+        // the blocks of the partial chunk are not scanned
+        assert!(result.cancelled);
+        assert_eq!(result.height, 0);
+        drop(tx);
+    }
+
+    #[test]
+    fn paused_scan_is_resumed_or_cancelled() {
+        let account = Account::from_seed(&Network::MainNetwork, 0, PHRASE).unwrap();
+        for cancel in [false, true] {
+            let chunks = Arc::new(Mutex::new(vec![]));
+            let listener = {
+                let chunks = chunks.clone();
+                move |p: &ScanProgress| chunks.lock().unwrap().push(p.height)
+            };
+            let handle = ScanHandle::with_listener(0, Arc::new(listener));
+            handle.pause();
+            let scan = {
+                let accounts = [account.clone()];
+                let blocks = blocks(&account, 30);
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    let source = MemorySource::new(blocks);
+                    let state = SyncState::new();
+                    scan_source(
+                        &Network::MainNetwork,
+                        &accounts,
+                        source,
+                        None,
+                        state,
+                        None,
+                        &handle,
+                    )
+                })
+            };
+            std::thread::sleep(Duration::from_millis(200));
+            assert!(!scan.is_finished());
+            assert!(chunks.lock().unwrap().is_empty());
+
+            if cancel {
+                handle.cancel();
+            } else {
+                handle.resume();
+            }
+            let result = scan.join().unwrap().unwrap();
+            assert_eq!(result.cancelled, cancel);
+            if cancel {
+                assert_eq!(result.height, 0);
+                assert!(chunks.lock().unwrap().is_empty());
+            } else {
+                assert_eq!(result.height, START_HEIGHT + 30);
+                assert_eq!(*chunks.lock().unwrap(), [START_HEIGHT + 30]);
+            }
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use super::keys::Account;
+use super::note::{ReceivedNote, SpentNote};
+use super::scan::{scan_accounts, scan_lightwalletd, Pool, ReorgError, ScanHandle, ScanResult};
+use super::state::SyncState;
+use super::Hash;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+use zcash_primitives::consensus::Network;
+
+/// Events reported by the [SyncService]
+#[derive(Debug)]
+pub enum SyncEvent {
+    /// The state is synchronized up to this height
+    Height(u32),
+    /// A note was received
+    Received(Pool, ReceivedNote),
+    /// A note was spent
+    Spent(Pool, SpentNote),
+    /// The block at this height is no longer on the chain. The state
+    /// was rewound before it and the blocks of the new chain are scanned.
+    /// A reorg of several blocks is reported once, with its first height
+    Reorg(u32),
+}
+
+/// Keeps the accounts synchronized with the tip of the chain
+///
+/// The service first finishes the scan of the warp data file, if any,
+/// then polls the lightwalletd server and scans the new blocks as they
+/// are mined. The state is saved in `state_file` after every chunk of
+/// blocks, so a service that is stopped resumes where it left off
+pub struct SyncService {
+    network: Network,
+    data_url: Option<String>,
+    lwd_url: String,
+    accounts: Vec<Account>,
+    state_file: String,
+    spam_threshold: u64,
+    poll_interval: Duration,
+    handle: ScanHandle,
+}
+
+impl SyncService {
+    /// `lwd_url` must support the spam filter, with the same `spam_threshold`
+    /// as the data file at `data_url`
+    pub fn new(
+        network: &Network,
+        data_url: Option<&str>,
+        lwd_url: &str,
+        accounts: &[Account],
+        state_file: &str,
+        spam_threshold: u64,
+        poll_interval: Duration,
+    ) -> Self {
+        SyncService {
+            network: *network,
+            data_url: data_url.map(str::to_string),
+            lwd_url: lwd_url.to_string(),
+            accounts: accounts.to_vec(),
+            state_file: state_file.to_string(),
+            spam_threshold,
+            poll_interval,
+            handle: ScanHandle::default(),
+        }
+    }
+
+    /// Keep the last `max_checkpoints` blocks as checkpoints, i.e. the
+    /// maximum depth of a reorg that the service recovers from
+    pub fn with_max_checkpoints(self, max_checkpoints: usize) -> Self {
+        SyncService {
+            handle: self.handle.with_max_checkpoints(max_checkpoints),
+            ..self
+        }
+    }
+
+    /// Handle that pauses or stops the service
+    pub fn handle(&self) -> ScanHandle {
+        self.handle.clone()
+    }
+
+    /// Run until the [ScanHandle] of the service is cancelled
+    ///
+    /// A scan in progress stops at the end of its current chunk of blocks.
+    /// Errors while following the tip are logged and retried at the next poll.
+    /// After a reorg, the state is rewound and the blocks are scanned again
+    /// right away until they connect to it
+    pub async fn run<F: FnMut(SyncEvent)>(&self, mut on_event: F) -> Result<()> {
+        let mut known = KnownNotes::load(&self.state_file)?;
+        if let Some(data_url) = self.data_url.as_ref() {
+            // a no-op once the state is after the end of the file
+            let result = self.scan_data_file(data_url).await?;
+            known.notify(&result, &mut on_event);
+        }
+        self.follow_tip(known, || self.scan_tip(), on_event).await
+    }
+
+    /// Scan the tip with `scan_tip` every poll interval until the handle
+    /// is cancelled, see [Self::run]
+    async fn follow_tip<T, Fut, F>(
+        &self,
+        mut known: KnownNotes,
+        mut scan_tip: T,
+        mut on_event: F,
+    ) -> Result<()>
+    where
+        T: FnMut() -> Fut,
+        Fut: Future<Output = Result<ScanResult>>,
+        F: FnMut(SyncEvent),
+    {
+        let mut reorg_height: Option<u32> = None;
+        while !self.handle.is_cancelled() {
+            let result = scan_tip().await;
+            if let Err(e) = &result {
+                if let Some(reorg) = e.downcast_ref::<ReorgError>() {
+                    // the rewound state no longer has the notes after the fork
+                    reorg_height = Some(reorg_height.map_or(reorg.height, |h| h.min(reorg.height)));
+                    known = KnownNotes::load(&self.state_file)?;
+                    continue;
+                }
+            }
+            if let Some(height) = reorg_height.take() {
+                on_event(SyncEvent::Reorg(height));
+            }
+            match result {
+                Ok(result) => known.notify(&result, &mut on_event),
+                Err(e) => log::warn!("Cannot sync with {}: {e}", self.lwd_url),
+            }
+            self.wait().await;
+        }
+        if let Some(height) = reorg_height {
+            on_event(SyncEvent::Reorg(height));
+        }
+        Ok(())
+    }
+
+    /// Finish the scan of the data file at `data_url`, on a blocking thread
+    /// because the scan of a data file does not yield to the runtime
+    async fn scan_data_file(&self, data_url: &str) -> Result<ScanResult> {
+        let network = self.network;
+        let data_url = data_url.to_string();
+        let accounts = self.accounts.clone();
+        let state_file = self.state_file.clone();
+        let handle = self.handle.clone();
+        tokio::task::spawn_blocking(move || {
+            scan_accounts(&network, &data_url, &accounts, Some(&state_file), &handle)
+        })
+        .await?
+    }
+
+    /// Scan the blocks mined since the last scan
+    async fn scan_tip(&self) -> Result<ScanResult> {
+        scan_lightwalletd(
+            &self.network,
+            &self.lwd_url,
+            &self.accounts,
+            None,
+            self.spam_threshold,
+            Some(&self.state_file),
+            &self.handle,
+        )
+        .await
+    }
+
+    /// Sleep for the poll interval, or until the service is cancelled
+    async fn wait(&self) {
+        let step = Duration::from_millis(100);
+        let mut waited = Duration::ZERO;
+        while waited < self.poll_interval && !self.handle.is_cancelled() {
+            tokio::time::sleep(step).await;
+            waited += step;
+        }
+    }
+}
+
+/// Notes already reported, by nullifier
+struct KnownNotes {
+    height: u32,
+    received: HashSet<Hash>,
+    spent: HashSet<Hash>,
+}
+
+impl KnownNotes {
+    fn load(state_file: &str) -> Result<Self> {
+        let mut known = KnownNotes {
+            height: 0,
+            received: HashSet::new(),
+            spent: HashSet::new(),
+        };
+        if let Some(state) = SyncState::load(state_file)? {
+            known.height = state.height;
+            known.received.extend(state.sapling.nfs.keys());
+            known.received.extend(state.orchard.nfs.keys());
+            let spent_notes = state
+                .sapling
+                .spent_notes
+                .iter()
+                .chain(state.orchard.spent_notes.iter());
+            for n in spent_notes {
+                known.received.insert(n.note.nf);
+                known.spent.insert(n.note.nf);
+            }
+        }
+        Ok(known)
+    }
+
+    /// Report the notes of `result` that are new
+    fn notify<F: FnMut(SyncEvent)>(&mut self, result: &ScanResult, on_event: &mut F) {
+        self.notify_pool(
+            Pool::Sapling,
+            result.sapling.notes.iter().map(|n| &n.note),
+            &result.sapling.spent_notes,
+            on_event,
+        );
+        self.notify_pool(
+            Pool::Orchard,
+            result.orchard.notes.iter().map(|n| &n.note),
+            &result.orchard.spent_notes,
+            on_event,
+        );
+        if result.height != self.height {
+            self.height = result.height;
+            on_event(SyncEvent::Height(result.height));
+        }
+    }
+
+    fn notify_pool<'a, F: FnMut(SyncEvent)>(
+        &mut self,
+        pool: Pool,
+        notes: impl Iterator<Item = &'a ReceivedNote>,
+        spent_notes: &'a [SpentNote],
+        on_event: &mut F,
+    ) {
+        let received = notes.chain(spent_notes.iter().map(|n| &n.note));
+        for n in received {
+            if self.received.insert(n.nf) {
+                on_event(SyncEvent::Received(pool, n.clone()));
+            }
+        }
+        for n in spent_notes.iter() {
+            if self.spent.insert(n.note.nf) {
+                on_event(SyncEvent::Spent(pool, n.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::scan::scan_source;
+    use super::super::source::MemorySource;
+    use super::super::testing::{block_hash, sapling_output, PHRASE, START_HEIGHT};
+    use super::*;
+    use crate::lw_rpc::{CompactBlock, CompactTx};
+    use std::cell::Cell;
+
+    /// Blocks after [START_HEIGHT] up to `end`, that fork at `fork`
+    /// and have a note for `account` at `note`
+    fn chain(account: &Account, end: u32, fork: u32, note: u32) -> Vec<CompactBlock> {
+        let hash = |height: u32| {
+            let mut hash = block_hash(START_HEIGHT + height);
+            if height >= fork {
+                hash[31] = 1;
+            }
+            hash
+        };
+        (1..=end)
+            .map(|i| {
+                let mut vtx = vec![];
+                if i == note {
+                    vtx.push(CompactTx {
+                        hash: hash(i),
+                        outputs: vec![sapling_output(account, 1000, i as u8)],
+                        ..CompactTx::default()
+                    });
+                }
+                CompactBlock {
+                    height: (START_HEIGHT + i) as u64,
+                    hash: hash(i),
+                    prev_hash: hash(i - 1),
+                    vtx,
+                    ..CompactBlock::default()
+                }
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reorg_is_rewound_and_reported() {
+        let network = Network::MainNetwork;
+        let accounts = [Account::from_seed(&network, 0, PHRASE).unwrap()];
+        let path = std::env::temp_dir().join(format!("warp2-reorg-{}.state", std::process::id()));
+        let state_file = path.to_str().unwrap();
+        let service =
+            SyncService::new(&network, None, "", &accounts, state_file, 0, Duration::ZERO);
+        let handle = service.handle();
+
+        // the chain forks at the 4th block after the first poll
+        let chains = [chain(&accounts[0], 5, 6, 3), chain(&accounts[0], 6, 4, 5)];
+        let polls = Cell::new(0);
+        let scan_tip = || {
+            let chain = &chains[polls.get().min(1)];
+            polls.set(polls.get() + 1);
+            let state = SyncState::load(state_file).unwrap().unwrap_or_default();
+            let blocks = chain
+                .iter()
+                .filter(|b| b.height > state.height as u64)
+                .cloned()
+                .collect();
+            let result = scan_source(
+                &network,
+                &accounts,
+                MemorySource::new(blocks),
+                None,
+                state,
+                Some(state_file),
+                &handle,
+            );
+            if polls.get() > 1 && result.is_ok() {
+                handle.cancel();
+            }
+            std::future::ready(result)
+        };
+        let mut events = vec![];
+        let known = KnownNotes::load(state_file).unwrap();
+        service
+            .follow_tip(known, scan_tip, |e| {
+                events.push(match e {
+                    SyncEvent::Height(height) => ("height", height),
+                    SyncEvent::Received(_, n) => ("received", n.height),
+                    SyncEvent::Spent(_, n) => ("spent", n.spent_height),
+                    SyncEvent::Reorg(height) => ("reorg", height),
+                })
+            })
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the state is rewound one block at a time until the new chain
+        // connects to it, and the reorg is reported once
+        assert_eq!(polls.get(), 4);
+        assert_eq!(
+            events,
+            [
+                ("received", START_HEIGHT + 3),
+                ("height", START_HEIGHT + 5),
+                ("reorg", START_HEIGHT + 4),
+                ("received", START_HEIGHT + 5),
+                ("height", START_HEIGHT + 6),
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use super::data::{
+    DataFileHeader, DataFileIndex, DataFileReader, HEADER_LEN, MAX_BLOCK_LEN, TRAILER_LEN,
+};
+use super::lwd::{
+    connect_lightwalletd, get_block_range, get_latest_height, get_transaction, get_transparent_txs,
+};
+use super::Hash;
+use crate::lw_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::lw_rpc::{CompactBlock, RawTransaction};
+use anyhow::Result;
+use byteorder::{ReadBytesExt, LE};
+use memmap2::Mmap;
+use prost::Message;
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufReader, Cursor, ErrorKind, Read};
+use std::path::Path;
+use tokio::runtime::Runtime;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+/// A source of compact blocks in increasing height order
+///
+/// Blocks are pulled one at a time by the scanner so that a source
+/// never reads further ahead than the scanner can process
+pub trait BlockSource: Send {
+    /// The next block, or None at the end of the source
+    fn next_block(&mut self) -> Result<Option<CompactBlock>>;
+}
+
+impl<S: BlockSource + ?Sized> BlockSource for Box<S> {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        (**self).next_block()
+    }
+}
+
+/// Read a plain block: u32 LE length followed by the protobuf
+/// encoded CompactBlock
+fn read_block<R: Read>(mut r: R) -> Result<Option<CompactBlock>> {
+    let len = match r.read_u32::<LE>() {
+        Ok(len) => len,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_BLOCK_LEN {
+        anyhow::bail!("Invalid block length {len}");
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    let cb = CompactBlock::decode(&*buf)?;
+    Ok(Some(cb))
+}
+
+/// Plain compact blocks, without bridges, on the local file system
+///
+/// This is the input of the producer
+pub struct PlainFileSource {
+    reader: BufReader<File>,
+}
+
+impl PlainFileSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(PlainFileSource {
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+impl BlockSource for PlainFileSource {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        read_block(&mut self.reader)
+    }
+}
+
+/// Data file on the local file system
+pub struct FileSource {
+    reader: DataFileReader<BufReader<File>>,
+}
+
+impl FileSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(FileSource {
+            reader: DataFileReader::open(BufReader::new(file))?,
+        })
+    }
+
+    /// Seek to the segment that contains `height`
+    pub fn open_at<P: AsRef<Path>>(path: P, height: u32) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(FileSource {
+            reader: DataFileReader::open_at(BufReader::new(file), height)?,
+        })
+    }
+
+    pub fn header(&self) -> &DataFileHeader {
+        self.reader.header()
+    }
+}
+
+impl BlockSource for FileSource {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        self.reader.next_block()
+    }
+}
+
+/// Memory mapped data file
+pub struct MmapSource {
+    reader: DataFileReader<Cursor<Mmap>>,
+}
+
+impl MmapSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(MmapSource {
+            reader: DataFileReader::open(Self::map(path)?)?,
+        })
+    }
+
+    /// Start at the segment that contains `height`
+    pub fn open_at<P: AsRef<Path>>(path: P, height: u32) -> Result<Self> {
+        Ok(MmapSource {
+            reader: DataFileReader::open_at(Self::map(path)?, height)?,
+        })
+    }
+
+    fn map<P: AsRef<Path>>(path: P) -> Result<Cursor<Mmap>> {
+        let file = File::open(path)?;
+        // Safety: the data file is not modified while it is scanned
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Cursor::new(mmap))
+    }
+
+    pub fn header(&self) -> &DataFileHeader {
+        self.reader.header()
+    }
+}
+
+impl BlockSource for MmapSource {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        self.reader.next_block()
+    }
+}
+
+/// Data file served over HTTP
+///
+/// The download can start at any segment of the file using range requests
+pub struct HttpSource {
+    reader: DataFileReader<Box<dyn Read + Send + Sync>>,
+}
+
+/// Request the bytes of `url` in `range`, as in the HTTP Range header
+fn get_range(url: &str, range: &str) -> Result<Box<dyn Read + Send + Sync>> {
+    let response = ureq::get(url)
+        .set("Range", &format!("bytes={range}"))
+        .call()?;
+    if response.status() != 206 {
+        anyhow::bail!("{url} does not support range requests");
+    }
+    Ok(response.into_reader())
+}
+
+impl HttpSource {
+    pub fn open(url: &str) -> Result<Self> {
+        let response = ureq::get(url).call()?;
+        Ok(HttpSource {
+            reader: DataFileReader::open(response.into_reader())?,
+        })
+    }
+
+    /// Only download the data file from the segment that contains `height`
+    ///
+    /// The header, the trailer and the index are fetched first. The whole
+    /// file is downloaded if the server does not support range requests
+    /// or if `height` is at the start of the file
+    pub fn open_at(url: &str, height: u32) -> Result<Self> {
+        let response = ureq::get(url)
+            .set("Range", &format!("bytes=0-{}", HEADER_LEN - 1))
+            .call()?;
+        if response.status() != 206 {
+            log::info!("{url} does not support range requests, downloading all of it");
+            return Ok(HttpSource {
+                reader: DataFileReader::open(response.into_reader())?,
+            });
+        }
+        let header = DataFileHeader::read(response.into_reader())?;
+        if height <= header.start_height {
+            return Self::open(url);
+        }
+        if height > header.end_height {
+            return Ok(HttpSource {
+                reader: DataFileReader::at_end(header, Box::new(std::io::empty())),
+            });
+        }
+        let index_offset =
+            DataFileIndex::read_trailer(get_range(url, &format!("-{TRAILER_LEN}"))?)?;
+        let index = DataFileIndex::read(get_range(url, &format!("{index_offset}-"))?)?;
+        let segments = index.segments_from(height)?;
+        let r = get_range(url, &format!("{}-", segments[0].offset))?;
+        Ok(HttpSource {
+            reader: DataFileReader::with_index(header, segments, r),
+        })
+    }
+
+    pub fn header(&self) -> &DataFileHeader {
+        self.reader.header()
+    }
+}
+
+impl BlockSource for HttpSource {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        self.reader.next_block()
+    }
+}
+
+/// Blocks streamed by a lightwalletd server
+///
+/// The source has its own runtime because it runs on the block reader
+/// thread. The connection is made when the first block is requested
+pub struct LwdSource {
+    rt: Runtime,
+    url: String,
+    start_height: u32,
+    end_height: Option<u32>,
+    spam_threshold: u64,
+    blocks: Option<Streaming<CompactBlock>>,
+    done: bool,
+}
+
+impl LwdSource {
+    /// Blocks from `start_height` to `end_height` inclusive, or to the tip
+    /// of the chain if `end_height` is None
+    ///
+    /// Transactions with more than `spam_threshold` inputs/outputs/actions
+    /// are replaced by bridges
+    pub fn new(
+        url: &str,
+        start_height: u32,
+        end_height: Option<u32>,
+        spam_threshold: u64,
+    ) -> Result<Self> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(LwdSource {
+            rt,
+            url: url.to_string(),
+            start_height,
+            end_height,
+            spam_threshold,
+            blocks: None,
+            done: false,
+        })
+    }
+}
+
+impl BlockSource for LwdSource {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        if self.done {
+            return Ok(None);
+        }
+        if self.blocks.is_none() {
+            let blocks = self.rt.block_on(async {
+                let mut client = connect_lightwalletd(&self.url).await?;
+                let end_height = match self.end_height {
+                    Some(end_height) => end_height,
+                    None => get_latest_height(&mut client).await?,
+                };
+                // lightwalletd returns the blocks in reverse order
+                // if the range is inverted
+                if self.start_height > end_height {
+                    return Ok(None);
+                }
+                let blocks = get_block_range(
+                    &mut client,
+                    self.start_height,
+                    end_height,
+                    self.spam_threshold,
+                )
+                .await?;
+                Ok::<_, anyhow::Error>(Some(blocks))
+            })?;
+            match blocks {
+                Some(blocks) => self.blocks = Some(blocks),
+                None => {
+                    self.done = true;
+                    return Ok(None);
+                }
+            }
+        }
+        let blocks = self.blocks.as_mut().unwrap();
+        let cb = self.rt.block_on(blocks.message())?;
+        self.done = cb.is_none();
+        Ok(cb)
+    }
+}
+
+/// Blocks held in memory
+pub struct MemorySource {
+    blocks: std::vec::IntoIter<CompactBlock>,
+}
+
+impl MemorySource {
+    pub fn new(blocks: Vec<CompactBlock>) -> Self {
+        MemorySource {
+            blocks: blocks.into_iter(),
+        }
+    }
+}
+
+impl BlockSource for MemorySource {
+    fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        Ok(self.blocks.next())
+    }
+}
+
+/// A source of full transactions
+pub trait TransactionSource {
+    /// The transaction with the given `txid`
+    fn get_transaction(
+        &mut self,
+        txid: &Hash,
+    ) -> impl Future<Output = Result<RawTransaction>> + Send;
+
+    /// The transactions that pay to or spend from the transparent `address`
+    /// between `start_height` and `end_height` (inclusive)
+    fn get_transparent_txs(
+        &mut self,
+        address: &str,
+        start_height: u32,
+        end_height: u32,
+    ) -> impl Future<Output = Result<Vec<RawTransaction>>> + Send;
+}
+
+/// Full transactions fetched from a lightwalletd server
+pub struct LwdTransactionSource {
+    client: CompactTxStreamerClient<Channel>,
+}
+
+impl LwdTransactionSource {
+    pub fn new(client: CompactTxStreamerClient<Channel>) -> Self {
+        LwdTransactionSource { client }
+    }
+}
+
+impl TransactionSource for LwdTransactionSource {
+    async fn get_transaction(&mut self, txid: &Hash) -> Result<RawTransaction> {
+        get_transaction(&mut self.client, txid).await
+    }
+
+    async fn get_transparent_txs(
+        &mut self,
+        address: &str,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<Vec<RawTransaction>> {
+        get_transparent_txs(&mut self.client, address, start_height, end_height).await
+    }
+}
+
+/// Full transactions from a local store, by txid and by
+/// transparent address
+pub struct MemoryTransactionSource {
+    txs: HashMap<Hash, RawTransaction>,
+    transparent_txs: HashMap<String, Vec<RawTransaction>>,
+}
+
+impl MemoryTransactionSource {
+    pub fn new(txs: HashMap<Hash, RawTransaction>) -> Self {
+        MemoryTransactionSource {
+            txs,
+            transparent_txs: HashMap::new(),
+        }
+    }
+
+    /// Add `tx` to the transactions of the transparent `address`
+    pub fn add_transparent_tx(&mut self, address: &str, tx: RawTransaction) {
+        self.transparent_txs
+            .entry(address.to_string())
+            .or_default()
+            .push(tx);
+    }
+}
+
+impl TransactionSource for MemoryTransactionSource {
+    async fn get_transaction(&mut self, txid: &Hash) -> Result<RawTransaction> {
+        self.txs
+            .get(txid)
+            .cloned()
+            .ok_or(anyhow::anyhow!("Unknown transaction {}", hex::encode(txid)))
+    }
+
+    async fn get_transparent_txs(
+        &mut self,
+        address: &str,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<Vec<RawTransaction>> {
+        let txs = self.transparent_txs.get(address).into_iter().flatten();
+        Ok(txs
+            .filter(|tx| (start_height as u64..=end_height as u64).contains(&tx.height))
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,390 @@
+use super::frontier::{orchard_tree_from_hex, sapling_tree_from_hex};
+use super::hasher::{OrchardHasher, SaplingHasher};
+use super::note::{ReceivedNote, SpentNote, SpentUtxo, Utxo};
+use super::{Hash, Hasher, MerkleTree, ReadWrite};
+use crate::lw_rpc::{CompactBlock, TreeState};
+use anyhow::Result;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// State of a shielded pool
+#[derive(Debug)]
+pub struct PoolState<H: Hasher> {
+    /// Commitment tree frontier and witnesses of the unspent notes
+    pub tree: MerkleTree<H>,
+    /// Unspent notes by nullifier
+    pub nfs: HashMap<Hash, ReceivedNote>,
+    pub spent_notes: Vec<SpentNote>,
+}
+
+impl<H: Hasher> PoolState<H> {
+    pub fn new(h: H) -> Self {
+        PoolState {
+            tree: MerkleTree::empty(h),
+            nfs: HashMap::new(),
+            spent_notes: vec![],
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        self.tree.write(&mut w)?;
+        w.write_u32::<LE>(self.nfs.len() as u32)?;
+        for n in self.nfs.values() {
+            n.write(&mut w)?;
+        }
+        w.write_u32::<LE>(self.spent_notes.len() as u32)?;
+        for n in self.spent_notes.iter() {
+            n.write(&mut w)?;
+        }
+        Ok(())
+    }
+
+    /// Rewind to the checkpoint at `height`
+    ///
+    /// Notes received after `height` are dropped and notes spent after it
+    /// become unspent again
+    pub fn rewind(&mut self, height: u32) -> Result<()> {
+        let checkpoint_height = self.tree.rewind(height)?;
+        if checkpoint_height != height {
+            anyhow::bail!("No checkpoint at height {height}");
+        }
+        self.nfs.retain(|_, n| n.height <= height);
+        let (unspent, spent): (Vec<_>, Vec<_>) = std::mem::take(&mut self.spent_notes)
+            .into_iter()
+            .partition(|n| n.spent_height > height);
+        self.spent_notes = spent;
+        for n in unspent {
+            if n.note.height <= height {
+                self.nfs.insert(n.note.nf, n.note);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R, h: H) -> Result<Self> {
+        let tree = MerkleTree::read(&mut r, h)?;
+        let len = r.read_u32::<LE>()? as usize;
+        let mut nfs = HashMap::new();
+        for _ in 0..len {
+            let n = ReceivedNote::read(&mut r)?;
+            nfs.insert(n.nf, n);
+        }
+        let len = r.read_u32::<LE>()? as usize;
+        let mut spent_notes = vec![];
+        for _ in 0..len {
+            spent_notes.push(SpentNote::read(&mut r)?);
+        }
+        Ok(PoolState {
+            tree,
+            nfs,
+            spent_notes,
+        })
+    }
+}
+
+/// State of the transparent addresses
+#[derive(Debug, Default)]
+pub struct TransparentState {
+    /// Height of the last block whose transactions were scanned
+    pub height: u32,
+    /// Unspent outputs by txid and output index
+    pub utxos: HashMap<(Hash, u32), Utxo>,
+    pub spent_utxos: Vec<SpentUtxo>,
+}
+
+impl TransparentState {
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LE>(self.height)?;
+        w.write_u32::<LE>(self.utxos.len() as u32)?;
+        for u in self.utxos.values() {
+            u.write(&mut w)?;
+        }
+        w.write_u32::<LE>(self.spent_utxos.len() as u32)?;
+        for u in self.spent_utxos.iter() {
+            u.write(&mut w)?;
+        }
+        Ok(())
+    }
+
+    /// Forget the transactions after `height`
+    ///
+    /// Outputs received after `height` are dropped and outputs spent after it
+    /// become unspent again
+    pub fn rewind(&mut self, height: u32) {
+        self.height = self.height.min(height);
+        self.utxos.retain(|_, u| u.height <= height);
+        let (unspent, spent): (Vec<_>, Vec<_>) = std::mem::take(&mut self.spent_utxos)
+            .into_iter()
+            .partition(|u| u.spent_height > height);
+        self.spent_utxos = spent;
+        for u in unspent {
+            if u.utxo.height <= height {
+                self.utxos.insert((u.utxo.txid, u.utxo.vout), u.utxo);
+            }
+        }
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let height = r.read_u32::<LE>()?;
+        let len = r.read_u32::<LE>()? as usize;
+        let mut utxos = HashMap::new();
+        for _ in 0..len {
+            let u = Utxo::read(&mut r)?;
+            utxos.insert((u.txid, u.vout), u);
+        }
+        let len = r.read_u32::<LE>()? as usize;
+        let mut spent_utxos = vec![];
+        for _ in 0..len {
+            spent_utxos.push(SpentUtxo::read(&mut r)?);
+        }
+        Ok(TransparentState {
+            height,
+            utxos,
+            spent_utxos,
+        })
+    }
+}
+
+/// Synchronization state persisted between scans
+///
+/// It is saved after every chunk of blocks so that an interrupted
+/// or later scan resumes after `height`
+#[derive(Debug)]
+pub struct SyncState {
+    /// Height of the last block scanned, 0 if nothing was scanned yet
+    pub height: u32,
+    /// Hash of the block at `height`
+    pub block_hash: Hash,
+    pub sapling: PoolState<SaplingHasher>,
+    pub orchard: PoolState<OrchardHasher>,
+    pub transparent: TransparentState,
+    /// Heights and hashes of the blocks where the trees were checkpointed
+    pub checkpoints: Vec<(u32, Hash)>,
+    /// Number of blocks that have a checkpoint. It is the maximum depth
+    /// of a reorg and of an anchor
+    pub max_checkpoints: usize,
+}
+
+/// Default number of checkpoints kept for rewinding and anchors
+pub const MAX_CHECKPOINTS: usize = 100;
+
+const STATE_MAGIC: &[u8; 4] = b"WST2";
+/// Version of the state file format, bumped when it changes
+const STATE_VERSION: u16 = 1;
+
+impl Default for SyncState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncState {
+    pub fn new() -> Self {
+        let mut state = SyncState {
+            height: 0,
+            block_hash: [0u8; 32],
+            sapling: PoolState::new(SaplingHasher::default()),
+            orchard: PoolState::new(OrchardHasher::default()),
+            transparent: TransparentState::default(),
+            checkpoints: vec![],
+            max_checkpoints: MAX_CHECKPOINTS,
+        };
+        // allow rewinding back to the initial state
+        state.checkpoint();
+        state
+    }
+
+    /// State after the block of `tree_state`, usually the block before
+    /// the birthday of the wallet
+    ///
+    /// The blocks up to that height do not have to be scanned
+    pub fn from_tree_state(tree_state: &TreeState) -> Result<Self> {
+        let mut block_hash: Hash = hex::decode(&tree_state.hash)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid block hash"))?;
+        // the RPC hash is displayed in reverse byte order
+        block_hash.reverse();
+        let mut state = SyncState {
+            height: tree_state.height as u32,
+            block_hash,
+            sapling: PoolState {
+                tree: sapling_tree_from_hex(&tree_state.sapling_tree)?,
+                nfs: HashMap::new(),
+                spent_notes: vec![],
+            },
+            orchard: PoolState {
+                tree: orchard_tree_from_hex(&tree_state.orchard_tree)?,
+                nfs: HashMap::new(),
+                spent_notes: vec![],
+            },
+            transparent: TransparentState {
+                height: tree_state.height as u32,
+                ..TransparentState::default()
+            },
+            checkpoints: vec![],
+            max_checkpoints: MAX_CHECKPOINTS,
+        };
+        state.checkpoint();
+        Ok(state)
+    }
+
+    /// Checkpoint the state at the current height
+    pub fn checkpoint(&mut self) {
+        let height = self.height;
+        self.sapling.tree.checkpoint(height, self.max_checkpoints);
+        self.orchard.tree.checkpoint(height, self.max_checkpoints);
+        self.add_checkpoint(height, self.block_hash);
+    }
+
+    /// Change the number of checkpoints, at least 1, and drop the
+    /// checkpoints outside of the new window
+    pub fn set_max_checkpoints(&mut self, max_checkpoints: usize) {
+        self.max_checkpoints = max_checkpoints.max(1);
+        self.sapling.tree.prune_checkpoints(self.max_checkpoints);
+        self.orchard.tree.prune_checkpoints(self.max_checkpoints);
+        let excess = self.checkpoints.len().saturating_sub(self.max_checkpoints);
+        self.checkpoints.drain(0..excess);
+    }
+
+    /// Height of the first block of a chunk ending at `end_height`
+    /// that gets a checkpoint
+    pub fn first_checkpoint_height(&self, end_height: u32) -> u32 {
+        (end_height + 1).saturating_sub(self.max_checkpoints as u32)
+    }
+
+    /// Record the hashes of the blocks from [SyncState::first_checkpoint_height]
+    /// and move the state to the last block
+    ///
+    /// The trees must have been checkpointed at these heights
+    pub fn checkpoint_blocks(&mut self, blocks: &[CompactBlock]) -> Result<()> {
+        let Some(last_block) = blocks.last() else {
+            return Ok(());
+        };
+        let from_height = self.first_checkpoint_height(last_block.height as u32);
+        for b in blocks.iter().filter(|b| b.height as u32 >= from_height) {
+            let hash: Hash = b
+                .hash
+                .clone()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid block hash"))?;
+            self.add_checkpoint(b.height as u32, hash);
+        }
+        self.sapling.tree.prune_checkpoints(self.max_checkpoints);
+        self.orchard.tree.prune_checkpoints(self.max_checkpoints);
+        self.height = last_block.height as u32;
+        self.block_hash = self.checkpoints.last().unwrap().1;
+        Ok(())
+    }
+
+    fn add_checkpoint(&mut self, height: u32, block_hash: Hash) {
+        self.checkpoints.retain(|c| c.0 < height);
+        self.checkpoints.push((height, block_hash));
+        if self.checkpoints.len() > self.max_checkpoints {
+            let excess = self.checkpoints.len() - self.max_checkpoints;
+            self.checkpoints.drain(0..excess);
+        }
+    }
+
+    /// Rewind to the last checkpoint at or before `height`
+    ///
+    /// Returns the height of the checkpoint, which is the new state height
+    pub fn rewind(&mut self, height: u32) -> Result<u32> {
+        let i = self
+            .checkpoints
+            .iter()
+            .rposition(|c| c.0 <= height)
+            .ok_or(anyhow::anyhow!("Cannot rewind to height {height}"))?;
+        self.checkpoints.truncate(i + 1);
+        let (height, block_hash) = self.checkpoints[i];
+        self.sapling.rewind(height)?;
+        self.orchard.rewind(height)?;
+        self.transparent.rewind(height);
+        self.height = height;
+        self.block_hash = block_hash;
+        Ok(height)
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(STATE_MAGIC)?;
+        w.write_u16::<LE>(STATE_VERSION)?;
+        w.write_u32::<LE>(self.height)?;
+        self.block_hash.write(&mut w)?;
+        self.sapling.write(&mut w)?;
+        self.orchard.write(&mut w)?;
+        self.transparent.write(&mut w)?;
+        w.write_u32::<LE>(self.checkpoints.len() as u32)?;
+        for (height, hash) in self.checkpoints.iter() {
+            w.write_u32::<LE>(*height)?;
+            hash.write(&mut w)?;
+        }
+        w.write_u32::<LE>(self.max_checkpoints as u32)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)
+            .map_err(|_| anyhow::anyhow!("Not a warp2 state file"))?;
+        if &magic != STATE_MAGIC {
+            anyhow::bail!("Not a warp2 state file");
+        }
+        let version = r.read_u16::<LE>()?;
+        if version != STATE_VERSION {
+            anyhow::bail!(
+                "Unsupported state file version {version}, expected {STATE_VERSION}. \
+                Delete the state file to scan again"
+            );
+        }
+        let height = r.read_u32::<LE>()?;
+        let block_hash = Hash::read(&mut r)?;
+        let sapling = PoolState::read(&mut r, SaplingHasher::default())?;
+        let orchard = PoolState::read(&mut r, OrchardHasher::default())?;
+        let transparent = TransparentState::read(&mut r)?;
+        let len = r.read_u32::<LE>()? as usize;
+        let mut checkpoints = vec![];
+        for _ in 0..len {
+            let height = r.read_u32::<LE>()?;
+            let hash = Hash::read(&mut r)?;
+            checkpoints.push((height, hash));
+        }
+        let max_checkpoints = r.read_u32::<LE>()? as usize;
+        Ok(SyncState {
+            height,
+            block_hash,
+            sapling,
+            orchard,
+            transparent,
+            checkpoints,
+            max_checkpoints,
+        })
+    }
+
+    /// Write the state to `path`
+    ///
+    /// The data goes to a temporary file first that is then renamed
+    /// so that a crash never leaves a partially written state
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+        self.write(&mut w)?;
+        w.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Read the state from `path`, None if the file does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let r = BufReader::new(File::open(path)?);
+        let state =
+            Self::read(r).map_err(|e| anyhow::anyhow!("Cannot load {}: {e}", path.display()))?;
+        Ok(Some(state))
+    }
+}
@@ -0,0 +1,246 @@
+//! Fixtures shared by the tests
+
+use super::keys::Account;
+use super::Hash;
+use crate::lw_rpc::{
+    CompactBlock, CompactOrchardAction, CompactSaplingOutput, CompactTx, RawTransaction,
+};
+use orchard::note::{ExtractedNoteCommitment, Nullifier, RandomSeed};
+use orchard::note_encryption::{OrchardDomain, OrchardNoteEncryption};
+use orchard::value::NoteValue;
+use rand::rngs::OsRng;
+use zcash_note_encryption::Domain;
+use zcash_primitives::consensus::{BranchId, Network};
+use zcash_primitives::keys::OutgoingViewingKey;
+use zcash_primitives::legacy::{Script, TransparentAddress};
+use zcash_primitives::memo::MemoBytes;
+use zcash_primitives::sapling::note_encryption::{sapling_note_encryption, SaplingDomain};
+use zcash_primitives::sapling::value::{ValueCommitTrapdoor, ValueCommitment};
+use zcash_primitives::sapling::{PaymentAddress, Rseed};
+use zcash_primitives::transaction::components::transparent::{self, OutPoint, TxIn, TxOut};
+use zcash_primitives::transaction::components::Amount;
+use zcash_primitives::transaction::Transaction;
+
+pub const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon \
+    abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+    abandon abandon abandon abandon abandon art";
+pub const START_HEIGHT: u32 = 2_000_000;
+
+/// Leaf number `i` of a test tree
+///
+/// 1 and 2 are the empty leaves of sapling and orchard, so the leaves
+/// start at 3. They are canonical nodes of both pools
+pub fn leaf(i: usize) -> Hash {
+    let mut h = [0u8; 32];
+    h[..8].copy_from_slice(&(i as u64 + 3).to_le_bytes());
+    h
+}
+
+pub fn block_hash(height: u32) -> Vec<u8> {
+    let mut hash = [0u8; 32];
+    hash[..4].copy_from_slice(&height.to_le_bytes());
+    hash.to_vec()
+}
+
+/// Sapling output of `value` to `address`
+pub fn sapling_output_to(address: &PaymentAddress, value: u64, seed: u8) -> CompactSaplingOutput {
+    let note = address.create_note(value, Rseed::AfterZip212([seed; 32]));
+    let enc =
+        sapling_note_encryption::<_, Network>(None, note.clone(), MemoBytes::empty(), &mut OsRng);
+    CompactSaplingOutput {
+        cmu: note.cmu().to_bytes().to_vec(),
+        epk: SaplingDomain::<Network>::epk_bytes(enc.epk()).0.to_vec(),
+        ciphertext: enc.encrypt_note_plaintext()[..52].to_vec(),
+    }
+}
+
+/// Full transaction with sapling outputs of a value and memo to an address,
+/// and its compact version
+///
+/// The outputs can be recovered with `ovk`. The proofs and signatures are
+/// zeros since they are never verified by the scanner
+pub fn sapling_tx(
+    ovk: Option<OutgoingViewingKey>,
+    outputs: &[(PaymentAddress, u64, MemoBytes)],
+    height: u32,
+) -> (CompactTx, RawTransaction) {
+    let mut data = tx_header();
+    // no transparent inputs, outputs nor sapling spends
+    data.extend([0, 0, 0, outputs.len() as u8]);
+    let mut compact_outputs = vec![];
+    for (i, (address, value, memo)) in outputs.iter().enumerate() {
+        let note = address.create_note(*value, Rseed::AfterZip212([i as u8 + 1; 32]));
+        let cv = ValueCommitment::derive(note.value(), ValueCommitTrapdoor::random(OsRng));
+        let cmu = note.cmu();
+        let enc = sapling_note_encryption::<_, Network>(ovk, note, memo.clone(), &mut OsRng);
+        let epk = SaplingDomain::<Network>::epk_bytes(enc.epk()).0;
+        let enc_ciphertext = enc.encrypt_note_plaintext();
+        data.extend(cv.to_bytes());
+        data.extend(cmu.to_bytes());
+        data.extend(epk);
+        data.extend(enc_ciphertext);
+        data.extend(enc.encrypt_outgoing_plaintext(&cv, &cmu, &mut OsRng));
+        compact_outputs.push(CompactSaplingOutput {
+            cmu: cmu.to_bytes().to_vec(),
+            epk: epk.to_vec(),
+            ciphertext: enc_ciphertext[..52].to_vec(),
+        });
+    }
+    if !outputs.is_empty() {
+        data.extend(0i64.to_le_bytes()); // value balance
+        data.extend(vec![0u8; 192 * outputs.len()]); // output proofs
+        data.extend([0u8; 64]); // binding signature
+    }
+    data.push(0); // no orchard actions
+    let tx = Transaction::read(&*data, BranchId::Nu5).unwrap();
+    let compact_tx = CompactTx {
+        hash: tx.txid().as_ref().to_vec(),
+        outputs: compact_outputs,
+        ..CompactTx::default()
+    };
+    let raw_tx = RawTransaction {
+        data,
+        height: height as u64,
+    };
+    (compact_tx, raw_tx)
+}
+
+/// Full transaction that spends the transparent outputs `inputs`, by txid
+/// and index, and has transparent outputs of a value to an address
+pub fn transparent_tx(
+    inputs: &[(Hash, u32)],
+    outputs: &[(TransparentAddress, u64)],
+    height: u32,
+) -> (Hash, RawTransaction) {
+    let mut data = tx_header();
+    data.push(inputs.len() as u8);
+    for (txid, n) in inputs.iter() {
+        let input = TxIn::<transparent::Authorized> {
+            prevout: OutPoint::new(*txid, *n),
+            script_sig: Script::default(),
+            sequence: u32::MAX,
+        };
+        input.write(&mut data).unwrap();
+    }
+    data.push(outputs.len() as u8);
+    for (address, value) in outputs.iter() {
+        let output = TxOut {
+            value: Amount::from_u64(*value).unwrap(),
+            script_pubkey: address.script(),
+        };
+        output.write(&mut data).unwrap();
+    }
+    data.extend([0, 0, 0]); // no sapling spends, outputs nor orchard actions
+    let tx = Transaction::read(&*data, BranchId::Nu5).unwrap();
+    let raw_tx = RawTransaction {
+        data,
+        height: height as u64,
+    };
+    (*tx.txid().as_ref(), raw_tx)
+}
+
+/// Header of a v5 transaction
+fn tx_header() -> Vec<u8> {
+    let mut data = vec![];
+    data.extend(0x8000_0005u32.to_le_bytes()); // v5, overwintered
+    data.extend(0x26A7_270Au32.to_le_bytes());
+    data.extend(u32::from(BranchId::Nu5).to_le_bytes());
+    data.extend([0u8; 8]); // lock time and expiry height
+    data
+}
+
+/// Sapling output of `value` to the default address of `account`
+pub fn sapling_output(account: &Account, value: u64, seed: u8) -> CompactSaplingOutput {
+    let (_, address) = account.keys.sapling.as_ref().unwrap().default_address();
+    sapling_output_to(&address, value, seed)
+}
+
+/// Orchard action of `value` to `address`, with the nullifier `nf`
+/// which is also the rho of the note
+pub fn orchard_action_to(address: &orchard::Address, value: u64, nf: Hash) -> CompactOrchardAction {
+    let rho = Nullifier::from_bytes(&nf).unwrap();
+    // not every seed is valid for a given rho
+    let note = (0u8..=255)
+        .find_map(|i| {
+            let rseed = Option::from(RandomSeed::from_bytes([i; 32], &rho))?;
+            Option::from(orchard::Note::from_parts(
+                *address,
+                NoteValue::from_raw(value),
+                rho,
+                rseed,
+            ))
+        })
+        .unwrap();
+    let mut memo = [0u8; 512];
+    memo[0] = 0xF6;
+    let enc = OrchardNoteEncryption::new(None, note, memo);
+    CompactOrchardAction {
+        nullifier: nf.to_vec(),
+        cmx: ExtractedNoteCommitment::from(note.commitment())
+            .to_bytes()
+            .to_vec(),
+        ephemeral_key: OrchardDomain::epk_bytes(enc.epk()).0.to_vec(),
+        ciphertext: enc.encrypt_note_plaintext()[..52].to_vec(),
+    }
+}
+
+/// Orchard action of `value` to the default address of `account`
+pub fn orchard_action(account: &Account, value: u64, nf: Hash) -> CompactOrchardAction {
+    let fvk = account.keys.orchard.as_ref().unwrap();
+    let address = fvk.address_at(0u32, orchard::keys::Scope::External);
+    orchard_action_to(&address, value, nf)
+}
+
+/// Blocks after [START_HEIGHT] with a transaction of a few outputs and
+/// actions each, and a transaction with a note for `account` in some of them
+pub fn blocks(account: &Account, count: u32) -> Vec<CompactBlock> {
+    let mut leaves = 0;
+    (1..=count)
+        .map(|i| {
+            let height = START_HEIGHT + i;
+            let outputs = (0..i % 4)
+                .map(|_| {
+                    leaves += 1;
+                    CompactSaplingOutput {
+                        cmu: leaf(leaves).to_vec(),
+                        epk: vec![0; 32],
+                        ciphertext: vec![0; 52],
+                    }
+                })
+                .collect();
+            let actions = (0..i % 3)
+                .map(|_| {
+                    leaves += 1;
+                    CompactOrchardAction {
+                        nullifier: leaf(leaves).to_vec(),
+                        cmx: leaf(leaves).to_vec(),
+                        ephemeral_key: vec![0; 32],
+                        ciphertext: vec![0; 52],
+                    }
+                })
+                .collect();
+            let mut vtx = vec![CompactTx {
+                hash: block_hash(height),
+                outputs,
+                actions,
+                ..CompactTx::default()
+            }];
+            if i % 5 == 2 {
+                let mut hash = block_hash(height);
+                hash[31] = 1;
+                vtx.push(CompactTx {
+                    hash,
+                    outputs: vec![sapling_output(account, 1000 * i as u64, i as u8)],
+                    ..CompactTx::default()
+                });
+            }
+            CompactBlock {
+                height: height as u64,
+                hash: block_hash(height),
+                prev_hash: block_hash(height - 1),
+                vtx,
+                ..CompactBlock::default()
+            }
+        })
+        .collect()
+}
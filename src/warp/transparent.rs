@@ -0,0 +1,241 @@
+use super::keys::{Account, TRANSPARENT_GAP_LIMIT};
+use super::note::{SpentUtxo, Utxo};
+use super::source::TransactionSource;
+use super::state::TransparentState;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use zcash_client_backend::encoding::encode_transparent_address_p;
+use zcash_primitives::consensus::{BlockHeight, BranchId, Network};
+use zcash_primitives::transaction::Transaction;
+use zcash_primitives::zip32::Scope;
+
+/// Scan the transactions of the transparent addresses of `accounts`
+/// that follow `state` up to `end_height` (inclusive)
+///
+/// The external and internal (change) chains of addresses are scanned until
+/// [TRANSPARENT_GAP_LIMIT] consecutive addresses have no transactions, past
+/// the last address that received an output before. The outputs received
+/// by the addresses become UTXOs and the UTXOs spent by the transactions
+/// are moved to the spent outputs
+pub async fn scan_transparent_txs<S: TransactionSource>(
+    network: &Network,
+    source: &mut S,
+    accounts: &[Account],
+    state: &mut TransparentState,
+    end_height: u32,
+) -> Result<()> {
+    if end_height <= state.height {
+        return Ok(());
+    }
+    let start_height = state.height + 1;
+
+    // the transactions of every address, ordered by height
+    let mut txs = BTreeMap::new();
+    let mut addresses = HashMap::new();
+    for a in accounts.iter().filter(|a| a.keys.transparent.is_some()) {
+        for scope in [Scope::External, Scope::Internal] {
+            // the addresses that were used before `start_height`
+            // do not count in the gap
+            let used = state
+                .utxos
+                .values()
+                .chain(state.spent_utxos.iter().map(|u| &u.utxo))
+                .filter(|u| u.account == a.id && u.scope == scope)
+                .map(|u| u.address_index + 1)
+                .max()
+                .unwrap_or(0);
+            let mut unused = 0;
+            let mut index = 0;
+            while unused < TRANSPARENT_GAP_LIMIT {
+                let address = a.keys.transparent_address(scope, index);
+                index += 1;
+                let Some(address) = address else {
+                    continue;
+                };
+                let encoded = encode_transparent_address_p(network, &address);
+                let address_txs = source
+                    .get_transparent_txs(&encoded, start_height, end_height)
+                    .await?;
+                if index <= used || !address_txs.is_empty() {
+                    unused = 0;
+                } else {
+                    unused += 1;
+                }
+                for raw_tx in address_txs {
+                    let height = raw_tx.height as u32;
+                    let branch_id = BranchId::for_height(network, BlockHeight::from_u32(height));
+                    let tx = Transaction::read(&*raw_tx.data, branch_id)?;
+                    txs.insert((height, *tx.txid().as_ref()), tx);
+                }
+                addresses.insert(address, (a.id, scope, index - 1));
+            }
+        }
+    }
+
+    let txs: Vec<_> = txs.into_iter().collect();
+    for block_txs in txs.chunk_by(|a, b| a.0 .0 == b.0 .0) {
+        // outputs first, since they can be spent in the same block
+        for ((height, txid), tx) in block_txs.iter() {
+            let Some(bundle) = tx.transparent_bundle() else {
+                continue;
+            };
+            for (vout, output) in bundle.vout.iter().enumerate() {
+                let Some(&(account, scope, address_index)) = output
+                    .recipient_address()
+                    .and_then(|address| addresses.get(&address))
+                else {
+                    continue;
+                };
+                let value = u64::from(output.value);
+                log::info!("Received {value} at {height} for {account}");
+                let vout = vout as u32;
+                state.utxos.insert(
+                    (*txid, vout),
+                    Utxo {
+                        account,
+                        scope,
+                        address_index,
+                        height: *height,
+                        txid: *txid,
+                        vout,
+                        value,
+                    },
+                );
+            }
+        }
+        for ((height, _), tx) in block_txs.iter() {
+            let Some(bundle) = tx.transparent_bundle() else {
+                continue;
+            };
+            for input in bundle.vin.iter() {
+                let outpoint = (*input.prevout.hash(), input.prevout.n());
+                if let Some(utxo) = state.utxos.remove(&outpoint) {
+                    log::info!("Spent {} at {height}", utxo.value);
+                    state.spent_utxos.push(SpentUtxo {
+                        utxo,
+                        spent_height: *height,
+                    });
+                }
+            }
+        }
+    }
+    state.height = end_height;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::source::MemoryTransactionSource;
+    use super::super::testing::{transparent_tx, PHRASE, START_HEIGHT};
+    use super::super::Hash;
+    use super::*;
+    use zcash_primitives::legacy::TransparentAddress;
+
+    /// Output of `value` to `address`, returns its txid
+    fn receive(
+        source: &mut MemoryTransactionSource,
+        address: &TransparentAddress,
+        value: u64,
+        height: u32,
+    ) -> Hash {
+        let (txid, tx) = transparent_tx(&[], &[(*address, value)], height);
+        let address = encode_transparent_address_p(&Network::MainNetwork, address);
+        source.add_transparent_tx(&address, tx);
+        txid
+    }
+
+    fn received(state: &TransparentState) -> Vec<(Scope, u32, u64)> {
+        let mut utxos: Vec<_> = state
+            .utxos
+            .values()
+            .map(|u| (u.scope, u.address_index, u.value))
+            .collect();
+        utxos.sort_by_key(|u| u.2);
+        utxos
+    }
+
+    #[tokio::test]
+    async fn addresses_are_scanned_up_to_the_gap_limit() {
+        let network = Network::MainNetwork;
+        let accounts = [Account::from_seed(&network, 0, PHRASE).unwrap()];
+        let address = |scope, index| accounts[0].keys.transparent_address(scope, index).unwrap();
+        let mut source = MemoryTransactionSource::new(HashMap::new());
+        let first = receive(
+            &mut source,
+            &address(Scope::External, 0),
+            1000,
+            START_HEIGHT + 1,
+        );
+        receive(
+            &mut source,
+            &address(Scope::Internal, 3),
+            2000,
+            START_HEIGHT + 2,
+        );
+        receive(
+            &mut source,
+            &address(Scope::External, 15),
+            3000,
+            START_HEIGHT + 3,
+        );
+        // more than 20 addresses after the last used one
+        receive(
+            &mut source,
+            &address(Scope::External, 40),
+            4000,
+            START_HEIGHT + 3,
+        );
+        let (_, spend) = transparent_tx(&[(first, 0)], &[], START_HEIGHT + 4);
+        source.add_transparent_tx(
+            &encode_transparent_address_p(&network, &address(Scope::External, 0)),
+            spend,
+        );
+
+        let mut state = TransparentState {
+            height: START_HEIGHT,
+            ..TransparentState::default()
+        };
+        scan_transparent_txs(
+            &network,
+            &mut source,
+            &accounts,
+            &mut state,
+            START_HEIGHT + 4,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            received(&state),
+            [(Scope::Internal, 3, 2000), (Scope::External, 15, 3000)]
+        );
+        assert_eq!(state.spent_utxos.len(), 1);
+        assert_eq!(state.spent_utxos[0].utxo.address_index, 0);
+        assert_eq!(state.spent_utxos[0].spent_height, START_HEIGHT + 4);
+
+        // the gap starts after the last used address,
+        // even if it has no new transactions
+        receive(
+            &mut source,
+            &address(Scope::External, 30),
+            5000,
+            START_HEIGHT + 5,
+        );
+        scan_transparent_txs(
+            &network,
+            &mut source,
+            &accounts,
+            &mut state,
+            START_HEIGHT + 5,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            received(&state),
+            [
+                (Scope::Internal, 3, 2000),
+                (Scope::External, 15, 3000),
+                (Scope::External, 30, 5000)
+            ]
+        );
+    }
+}
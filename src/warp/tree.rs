@@ -1,16 +1,93 @@
 use super::bridge::{Bridge, CompactLayer};
+use super::hasher::check_nodes;
 use super::witness::Witness;
-use super::{Hasher, Path, ReadWrite, DEPTH};
+use super::{empty_roots, Hasher, Path, ReadWrite, DEPTH};
 use anyhow::Result;
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use std::fmt::Debug;
 use std::io::{Read, Write};
 
+/// Snapshot of the tree after the block at `height`
+///
+/// The witnesses are not copied. Fills are only ever appended to a witness,
+/// so a witness at the checkpoint is the current one without its last fills.
+/// The witnesses of the notes spent after the checkpoint are moved to
+/// `removed`, see [MerkleTree::remove_witness]
+#[derive(Debug)]
+pub struct Checkpoint<H: Hasher> {
+    pub height: u32,
+    pub pos: usize,
+    pub prev: [H::D; DEPTH + 1],
+    pub leaf: Option<H::D>,
+    pub ommers: [H::D; DEPTH],
+    /// Position and number of fills of the witnesses
+    pub witnesses: Vec<(usize, usize)>,
+    /// Witnesses removed after the checkpoint that this checkpoint
+    /// or an earlier one still has
+    pub removed: Vec<Witness<H>>,
+}
+
+impl<H: Hasher> Checkpoint<H> {
+    fn has_witness(&self, pos: usize) -> bool {
+        self.witnesses.iter().any(|(p, _)| *p == pos)
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LE>(self.height)?;
+        write_frontier::<H, _>(self.pos, &self.prev, &self.leaf, &self.ommers, &mut w)?;
+        w.write_u32::<LE>(self.witnesses.len() as u32)?;
+        for (pos, fills) in self.witnesses.iter() {
+            w.write_u64::<LE>(*pos as u64)?;
+            w.write_u32::<LE>(*fills as u32)?;
+        }
+        write_witnesses(&self.removed, &mut w)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut r: R, h: &H) -> Result<Self> {
+        let height = r.read_u32::<LE>()?;
+        let (pos, prev, leaf, ommers) = read_frontier(&mut r, h)?;
+        let len = r.read_u32::<LE>()? as usize;
+        let mut witnesses = vec![];
+        for _ in 0..len {
+            let p = r.read_u64::<LE>()? as usize;
+            let fills = r.read_u32::<LE>()? as usize;
+            if p >= pos {
+                anyhow::bail!("Witness at position {p} is outside of a tree of size {pos}");
+            }
+            witnesses.push((p, fills));
+        }
+        let removed = read_witnesses(pos, &mut r, h)?;
+        Ok(Checkpoint {
+            height,
+            pos,
+            prev,
+            leaf,
+            ommers,
+            witnesses,
+            removed,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct MerkleTree<H: Hasher> {
     pub pos: usize,
     pub prev: [H::D; DEPTH + 1],
+    /// Last leaf of the tree
+    ///
+    /// It is unknown, like the ommers, after a bridge that does not have
+    /// them until more leaves are added
+    pub leaf: Option<H::D>,
+    /// Last left node at every depth
+    ///
+    /// When the last leaf is a right leaf, its pair and the ommers
+    /// below the first 0 bit of its position are combined into `prev`.
+    /// They are kept here to build the frontier of the tree
+    pub ommers: [H::D; DEPTH],
     pub witnesses: Vec<Witness<H>>,
+    /// Snapshots by increasing height, used to rewind the tree on reorgs
+    pub checkpoints: Vec<Checkpoint<H>>,
     pub h: H,
 }
 
@@ -19,7 +96,10 @@ impl<H: Hasher> MerkleTree<H> {
         MerkleTree {
             pos: 0,
             prev: std::array::from_fn(|_| h.empty()),
+            leaf: Some(h.empty()),
+            ommers: std::array::from_fn(|_| h.empty()),
             witnesses: vec![],
+            checkpoints: vec![],
             h,
         }
     }
@@ -36,7 +116,7 @@ impl<H: Hasher> MerkleTree<H> {
                 self.witnesses.push(Witness {
                     path: Path {
                         pos: self.pos + i,
-                        value: n.0.clone(),
+                        value: n.0,
                         siblings: vec![],
                     },
                     fills: vec![],
@@ -49,38 +129,42 @@ impl<H: Hasher> MerkleTree<H> {
         let mut layer = vec![];
         let mut fill = self.h.empty();
         if !self.h.is_empty(&self.prev[0]) {
-            layer.push(self.prev[0].clone());
-            fill = nodes[0].0.clone();
+            layer.push(self.prev[0]);
+            fill = nodes[0].0;
         }
-        layer.extend(nodes.iter().map(|n| n.0.clone()));
+        layer.extend(nodes.iter().map(|n| n.0));
 
         for depth in 0..DEPTH {
             // println!("Merging {depth}");
             let mut new_fill = self.h.empty();
             let len = layer.len();
             let start = (self.pos >> depth) & 0xFFFF_FFFE;
+            // start is even, so are the left nodes of the layer
+            if let Some(l) = layer.iter().step_by(2).rev().find(|n| !self.h.is_empty(n)) {
+                self.ommers[depth] = *l;
+            }
             for &wi in new_witnesses.iter() {
                 let w = &mut self.witnesses[wi];
                 let i = (w.path.pos >> depth) - start;
                 if i & 1 == 1 {
                     assert_ne!(layer[i - 1], self.h.empty());
-                    w.path.siblings.push(layer[i - 1].clone());
+                    w.path.siblings.push(layer[i - 1]);
                 }
             }
             for w in self.witnesses.iter_mut() {
                 if (w.path.pos >> depth) >= start {
                     let i = (w.path.pos >> depth) - start;
                     if i & 1 == 0 && i < len - 1 && !self.h.is_empty(&layer[i + 1]) {
-                        w.fills.push(layer[i + 1].clone());
+                        w.fills.push(layer[i + 1]);
                     }
                 }
             }
             log::debug!("w {:?}", self.witnesses);
 
-            let pairs = (len + 1) / 2;
+            let pairs = len.div_ceil(2);
             let mut new_layer = vec![];
             if !self.h.is_empty(&self.prev[depth + 1]) {
-                new_layer.push(self.prev[depth + 1].clone());
+                new_layer.push(self.prev[depth + 1]);
             }
             self.prev[depth] = self.h.empty();
             new_layer.extend_from_slice(&self.h.parallel_combine(depth as u8, &layer, pairs - 1));
@@ -91,24 +175,24 @@ impl<H: Hasher> MerkleTree<H> {
                 if 2 * i + 1 < len {
                     if !self.h.is_empty(&layer[2 * i + 1]) {
                         let hn = self.h.combine(depth as u8, l, &layer[2 * i + 1], true);
-                        new_layer.push(hn.clone());
+                        new_layer.push(hn);
                     } else {
                         new_layer.push(self.h.empty());
-                        self.prev[depth] = l.clone();
+                        self.prev[depth] = *l;
                     }
                 } else {
                     if !self.h.is_empty(l) {
-                        self.prev[depth] = l.clone();
+                        self.prev[depth] = *l;
                     }
                     new_layer.push(self.h.empty());
                 }
             }
             if new_layer.len() >= 2 && !self.h.is_empty(&new_layer[1]) {
-                new_fill = new_layer[1].clone();
+                new_fill = new_layer[1];
             }
 
             compact_layers.push(CompactLayer {
-                prev: self.prev[depth].clone(),
+                prev: self.prev[depth],
                 fill,
             });
 
@@ -118,12 +202,15 @@ impl<H: Hasher> MerkleTree<H> {
         }
         let pos = self.pos;
         self.pos += nodes.len();
+        self.leaf = Some(nodes[nodes.len() - 1].0);
         Bridge {
             height,
             block_len,
             pos,
             len: nodes.len(),
             layers: compact_layers.try_into().unwrap(),
+            leaf: self.leaf,
+            ommers: self.ommers[..combined_ommers(self.pos)].to_vec(),
         }
     }
 
@@ -134,57 +221,528 @@ impl<H: Hasher> MerkleTree<H> {
                 for w in self.witnesses.iter_mut() {
                     let p = w.path.pos >> h;
                     if p & 1 == 0 && p >> 1 == s {
-                        w.fills.push(bridge.layers[h].fill.clone());
+                        w.fills.push(bridge.layers[h].fill);
                     }
                 }
             }
-            self.prev[h] = bridge.layers[h].prev.clone();
+            self.prev[h] = bridge.layers[h].prev;
         }
         self.pos += bridge.len;
+        self.leaf = bridge.leaf;
+        for (o, bo) in self.ommers.iter_mut().zip(bridge.ommers.iter()) {
+            *o = *bo;
+        }
     }
 
     pub fn edge(&self, empty_roots: &[H::D]) -> [H::D; DEPTH] {
-        let mut path = vec![];
-        let mut h = self.h.empty();
-        for depth in 0..DEPTH {
-            let n = &self.prev[depth];
-            if !self.h.is_empty(n) {
-                h = self.h.combine(depth as u8, n, &h, false);
-            } else {
-                h = self.h.combine(depth as u8, &h, &empty_roots[depth], false);
-            }
-            path.push(h.clone());
-        }
-        path.try_into().unwrap()
+        edge(&self.prev, empty_roots, &self.h)
     }
 
     pub fn add_witness(&mut self, w: Witness<H>) {
         self.witnesses.push(w);
     }
-    pub fn remove_witness(&mut self, pos: usize) {
-        self.witnesses.retain(|w| w.path.pos != pos);
+
+    /// Remove the witness of the note at `pos`, spent at `height`
+    ///
+    /// The checkpoints at or after `height` lose the witness too. It is kept
+    /// in the last checkpoint before `height` that has it, to rewind to them
+    pub fn remove_witness(&mut self, pos: usize, height: u32) {
+        let Some(i) = self.witnesses.iter().position(|w| w.path.pos == pos) else {
+            return;
+        };
+        let w = self.witnesses.remove(i);
+        let mut keeper = None;
+        for (i, c) in self.checkpoints.iter_mut().enumerate() {
+            if c.height >= height {
+                c.witnesses.retain(|(p, _)| *p != pos);
+            } else if c.has_witness(pos) {
+                keeper = Some(i);
+            }
+        }
+        if let Some(i) = keeper {
+            self.checkpoints[i].removed.push(w);
+        }
+    }
+
+    /// Record the current state of the tree as of `height`
+    ///
+    /// Only the last `max_checkpoints` checkpoints are kept
+    pub fn checkpoint(&mut self, height: u32, max_checkpoints: usize) {
+        // the removed witnesses of the replaced checkpoints may still
+        // be needed by the earlier ones
+        let mut removed = vec![];
+        while self.checkpoints.last().is_some_and(|c| c.height >= height) {
+            removed.append(&mut self.checkpoints.pop().unwrap().removed);
+        }
+        removed.retain(|w| self.checkpoints.iter().any(|c| c.has_witness(w.path.pos)));
+        self.checkpoints.push(Checkpoint {
+            height,
+            pos: self.pos,
+            prev: self.prev,
+            leaf: self.leaf,
+            ommers: self.ommers,
+            witnesses: self
+                .witnesses
+                .iter()
+                .map(|w| (w.path.pos, w.fills.len()))
+                .collect(),
+            removed,
+        });
+        self.prune_checkpoints(max_checkpoints);
+    }
+
+    /// The witness at `pos` as of the checkpoint at index `i`
+    fn checkpoint_witness(&self, i: usize, pos: usize) -> Result<Witness<H>> {
+        let c = &self.checkpoints[i];
+        let fills = c
+            .witnesses
+            .iter()
+            .find(|(p, _)| *p == pos)
+            .map(|(_, fills)| *fills)
+            .ok_or(anyhow::anyhow!(
+                "No witness for {pos} at height {}",
+                c.height
+            ))?;
+        let w = self
+            .witnesses
+            .iter()
+            .chain(self.checkpoints[i..].iter().flat_map(|c| c.removed.iter()))
+            .find(|w| w.path.pos == pos)
+            .filter(|w| w.fills.len() >= fills)
+            .ok_or(anyhow::anyhow!(
+                "Missing witness for {pos} at height {}",
+                c.height
+            ))?;
+        let mut w = w.clone();
+        w.fills.truncate(fills);
+        Ok(w)
     }
 
+    /// Only keep the last `max_checkpoints` checkpoints
+    pub fn prune_checkpoints(&mut self, max_checkpoints: usize) {
+        if self.checkpoints.len() > max_checkpoints {
+            let excess = self.checkpoints.len() - max_checkpoints;
+            self.checkpoints.drain(0..excess);
+        }
+    }
+
+    /// Restore the tree to the last checkpoint at or before `height`
+    ///
+    /// Returns the height of the checkpoint. Checkpoints after it are dropped
+    pub fn rewind(&mut self, height: u32) -> Result<u32> {
+        let i = self
+            .checkpoints
+            .iter()
+            .rposition(|c| c.height <= height)
+            .ok_or(anyhow::anyhow!(
+                "No checkpoint at or before height {height}"
+            ))?;
+        let witnesses = self.checkpoints[i]
+            .witnesses
+            .iter()
+            .map(|(pos, _)| self.checkpoint_witness(i, *pos))
+            .collect::<Result<Vec<_>>>()?;
+        // the removed witnesses that are not restored may still
+        // be needed by the earlier checkpoints, the others are dropped
+        let mut removed: Vec<_> = self
+            .checkpoints
+            .drain(i + 1..)
+            .flat_map(|c| c.removed)
+            .collect();
+        let (earlier, c) = self.checkpoints.split_at_mut(i);
+        let c = &mut c[0];
+        removed.append(&mut c.removed);
+        removed.retain(|w| {
+            let pos = w.path.pos;
+            !c.has_witness(pos) && earlier.iter().any(|c| c.has_witness(pos))
+        });
+        c.removed = removed;
+        self.pos = c.pos;
+        self.prev = c.prev;
+        self.leaf = c.leaf;
+        self.ommers = c.ommers;
+        self.witnesses = witnesses;
+        Ok(c.height)
+    }
+
+    /// The checkpoint at `height`, which must be one of the
+    /// last `max_checkpoints` heights
+    pub fn checkpoint_at(&self, height: u32) -> Result<&Checkpoint<H>> {
+        match (self.checkpoints.first(), self.checkpoints.last()) {
+            (Some(first), Some(last)) => self
+                .checkpoints
+                .iter()
+                .find(|c| c.height == height)
+                .ok_or(anyhow::anyhow!(
+                    "Height {height} is outside of the checkpoint window {}-{}",
+                    first.height,
+                    last.height
+                )),
+            _ => anyhow::bail!("No checkpoint"),
+        }
+    }
+
+    /// Root of the tree at `height`
+    pub fn anchor_at(&self, height: u32) -> Result<H::D> {
+        let c = self.checkpoint_at(height)?;
+        let er = empty_roots(&self.h);
+        Ok(edge(&c.prev, &er, &self.h)[DEPTH - 1])
+    }
+
+    /// Root and Merkle path of the note at `pos` for the anchor at `height`
+    pub fn witness_root_at(&self, pos: usize, height: u32) -> Result<(H::D, [H::D; DEPTH])> {
+        let c = self.checkpoint_at(height)?;
+        let i = self
+            .checkpoints
+            .iter()
+            .position(|c| c.height == height)
+            .unwrap();
+        let w = self.checkpoint_witness(i, pos)?;
+        let er = empty_roots(&self.h);
+        let edge = edge(&c.prev, &er, &self.h);
+        Ok(w.root(&er, &edge, &self.h))
+    }
+
+    /// Serialize the tree with its witnesses and checkpoints
+    ///
+    /// The data starts with [TREE_VERSION] and the [Hasher::ID] of the tree
     pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
-        w.write_u64::<LE>(self.pos as u64)?;
-        for p in self.prev.iter() {
-            p.write(&mut w)?;
+        w.write_u8(TREE_VERSION)?;
+        w.write_u8(H::ID)?;
+        write_frontier::<H, _>(self.pos, &self.prev, &self.leaf, &self.ommers, &mut w)?;
+        write_witnesses(&self.witnesses, &mut w)?;
+        w.write_u32::<LE>(self.checkpoints.len() as u32)?;
+        for c in self.checkpoints.iter() {
+            c.write(&mut w)?;
         }
         Ok(())
     }
 
     pub fn read<R: Read>(mut r: R, h: H) -> Result<Self> {
-        let pos = r.read_u64::<LE>()? as usize;
-        let mut prev = vec![];
-        for _ in 0..DEPTH + 1 {
-            let p = H::D::read(&mut r)?;
-            prev.push(p);
+        let version = r.read_u8()?;
+        if version != TREE_VERSION {
+            anyhow::bail!("Unsupported tree version {version}");
+        }
+        let id = r.read_u8()?;
+        if id != H::ID {
+            anyhow::bail!("The tree was written by hasher {id} instead of {}", H::ID);
         }
-        Ok(Self {
+        let (pos, prev, leaf, ommers) = read_frontier(&mut r, &h)?;
+        let witnesses = read_witnesses(pos, &mut r, &h)?;
+        let len = r.read_u32::<LE>()? as usize;
+        let mut checkpoints: Vec<Checkpoint<H>> = vec![];
+        for _ in 0..len {
+            let c = Checkpoint::read(&mut r, &h)?;
+            if c.pos > pos || checkpoints.last().map(|p| p.height >= c.height) == Some(true) {
+                anyhow::bail!("Invalid checkpoint at height {}", c.height);
+            }
+            checkpoints.push(c);
+        }
+        let tree = Self {
             pos,
-            prev: prev.try_into().unwrap(),
-            witnesses: vec![],
+            prev,
+            leaf,
+            ommers,
+            witnesses,
+            checkpoints,
             h,
-        })
+        };
+        for (i, c) in tree.checkpoints.iter().enumerate() {
+            for (pos, _) in c.witnesses.iter() {
+                tree.checkpoint_witness(i, *pos)?;
+            }
+        }
+        Ok(tree)
+    }
+}
+
+/// Version of the serialization format of [MerkleTree]
+pub const TREE_VERSION: u8 = 1;
+
+/// Number of ommers of the frontier of a tree of size `pos` that are
+/// combined into `prev`, counting the left sibling of the last leaf
+///
+/// It is the number of trailing 1 bits of the position of the last leaf
+pub(super) fn combined_ommers(pos: usize) -> usize {
+    if pos == 0 {
+        0
+    } else {
+        (pos.trailing_zeros() as usize).min(DEPTH)
+    }
+}
+
+/// Write the frontier of a tree of size `pos`, with only the ommers
+/// that are not in `prev`
+fn write_frontier<H: Hasher, W: Write>(
+    pos: usize,
+    prev: &[H::D; DEPTH + 1],
+    leaf: &Option<H::D>,
+    ommers: &[H::D; DEPTH],
+    mut w: W,
+) -> Result<()> {
+    w.write_u64::<LE>(pos as u64)?;
+    for p in prev.iter() {
+        p.write(&mut w)?;
+    }
+    match leaf {
+        Some(leaf) => {
+            w.write_u8(1)?;
+            leaf.write(&mut w)?;
+            for o in ommers[..combined_ommers(pos)].iter() {
+                o.write(&mut w)?;
+            }
+        }
+        None => w.write_u8(0)?,
+    }
+    Ok(())
+}
+
+type FrontierParts<H> = (
+    usize,
+    [<H as Hasher>::D; DEPTH + 1],
+    Option<<H as Hasher>::D>,
+    [<H as Hasher>::D; DEPTH],
+);
+
+/// Read the size, `prev`, last leaf and ommers of a tree
+///
+/// The leaf and the ommers are only present if they are known
+fn read_frontier<H: Hasher, R: Read>(mut r: R, h: &H) -> Result<FrontierParts<H>> {
+    let pos = r.read_u64::<LE>()?;
+    if pos > 1 << DEPTH {
+        anyhow::bail!("Invalid tree size {pos}");
+    }
+    let pos = pos as usize;
+    let mut prev = vec![];
+    for _ in 0..DEPTH + 1 {
+        prev.push(H::D::read(&mut r)?);
+    }
+    let mut leaf = None;
+    let mut ommers: [H::D; DEPTH] = std::array::from_fn(|_| H::D::default());
+    if r.read_u8()? != 0 {
+        leaf = Some(H::D::read(&mut r)?);
+        for o in ommers[..combined_ommers(pos)].iter_mut() {
+            *o = H::D::read(&mut r)?;
+        }
+    }
+    check_nodes(h, &prev)?;
+    check_nodes(h, leaf.as_slice())?;
+    check_nodes(h, &ommers)?;
+    Ok((pos, prev.try_into().unwrap(), leaf, ommers))
+}
+
+fn write_witnesses<H: Hasher, W: Write>(witnesses: &[Witness<H>], mut w: W) -> Result<()> {
+    w.write_u32::<LE>(witnesses.len() as u32)?;
+    for witness in witnesses.iter() {
+        witness.write(&mut w)?;
+    }
+    Ok(())
+}
+
+/// Read the witnesses of a tree of size `pos`
+fn read_witnesses<H: Hasher, R: Read>(pos: usize, mut r: R, h: &H) -> Result<Vec<Witness<H>>> {
+    let len = r.read_u32::<LE>()? as usize;
+    let mut witnesses = vec![];
+    for _ in 0..len {
+        let witness = Witness::read(&mut r, h)?;
+        if witness.path.pos >= pos {
+            anyhow::bail!(
+                "Witness at position {} is outside of a tree of size {pos}",
+                witness.path.pos
+            );
+        }
+        witnesses.push(witness);
+    }
+    Ok(witnesses)
+}
+
+/// Right edge of the tree with the frontier `prev`
+///
+/// `edge[depth]` is the node at `depth + 1` of the last subtree that is
+/// not complete, the top one being the root of the tree
+fn edge<H: Hasher>(prev: &[H::D; DEPTH + 1], empty_roots: &[H::D], h: &H) -> [H::D; DEPTH] {
+    let mut path = vec![];
+    let mut node = h.empty();
+    for (depth, (n, er)) in prev.iter().zip(empty_roots).take(DEPTH).enumerate() {
+        if !h.is_empty(n) {
+            node = h.combine(depth as u8, n, &node, false);
+        } else {
+            node = h.combine(depth as u8, &node, er, false);
+        }
+        path.push(node);
+    }
+    path.try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warp::hasher::{OrchardHasher, SaplingHasher};
+    use crate::warp::testing::leaf;
+    use crate::warp::Hash;
+
+    fn copy(tree: &MerkleTree<SaplingHasher>) -> MerkleTree<SaplingHasher> {
+        let mut data = vec![];
+        tree.write(&mut data).unwrap();
+        MerkleTree::read(&*data, SaplingHasher::default()).unwrap()
+    }
+
+    fn witness_bytes(witnesses: &[Witness<SaplingHasher>]) -> Vec<Vec<u8>> {
+        witnesses
+            .iter()
+            .map(|w| {
+                let mut data = vec![];
+                w.write(&mut data).unwrap();
+                data
+            })
+            .collect()
+    }
+
+    /// State of the tree after a block: anchor, witnesses and their roots
+    struct Snapshot {
+        anchor: Hash,
+        witnesses: Vec<Vec<u8>>,
+        roots: Vec<(usize, (Hash, [Hash; DEPTH]))>,
+    }
+
+    fn snapshot(tree: &MerkleTree<SaplingHasher>) -> Snapshot {
+        let er = empty_roots(&tree.h);
+        let edge = tree.edge(&er);
+        Snapshot {
+            anchor: edge[DEPTH - 1],
+            witnesses: witness_bytes(&tree.witnesses),
+            roots: tree
+                .witnesses
+                .iter()
+                .map(|w| (w.path.pos, w.root(&er, &edge, &tree.h)))
+                .collect(),
+        }
+    }
+
+    /// Blocks 1 to 12 with a few notes, some of which are spent later,
+    /// and the snapshots of the tree after every block
+    fn blocks() -> (MerkleTree<SaplingHasher>, Vec<Snapshot>, Vec<(usize, u32)>) {
+        let received = [0, 3, 4, 9, 17, 18, 30];
+        let spent = [(3, 5), (17, 7), (0, 9), (30, 12)];
+        let mut tree = MerkleTree::empty(SaplingHasher::default());
+        tree.checkpoint(0, 100);
+        let mut snapshots = vec![snapshot(&tree)];
+        let mut size = 0;
+        for height in 1..=12u32 {
+            let len = 1 + (height as usize * 5) % 7;
+            let nodes: Vec<_> = (size..size + len)
+                .map(|i| (leaf(i), received.contains(&i)))
+                .collect();
+            tree.add_nodes(height, 0, &nodes);
+            size += len;
+            for (pos, _) in spent.iter().filter(|(_, h)| *h == height) {
+                tree.remove_witness(*pos, height);
+            }
+            tree.checkpoint(height, 100);
+            snapshots.push(snapshot(&tree));
+        }
+        (tree, snapshots, spent.to_vec())
+    }
+
+    #[test]
+    fn checkpoints_keep_the_witnesses_of_spent_notes() {
+        let (tree, snapshots, spent) = blocks();
+        let tree = copy(&tree);
+        for (height, s) in snapshots.iter().enumerate() {
+            let height = height as u32;
+            assert_eq!(tree.anchor_at(height).unwrap(), s.anchor);
+            for (pos, root) in s.roots.iter() {
+                assert_eq!(&tree.witness_root_at(*pos, height).unwrap(), root);
+            }
+        }
+        for (pos, spent_height) in spent {
+            assert!(tree.witness_root_at(pos, spent_height).is_err());
+            assert!(tree.witness_root_at(pos, spent_height - 1).is_ok());
+        }
+    }
+
+    #[test]
+    fn rewind_restores_the_witnesses() {
+        let (tree, snapshots, _) = blocks();
+        for (height, s) in snapshots.iter().enumerate().rev() {
+            let mut tree = copy(&tree);
+            assert_eq!(tree.rewind(height as u32).unwrap(), height as u32);
+            assert_eq!(tree.checkpoints.len(), height + 1);
+            assert_eq!(witness_bytes(&tree.witnesses), s.witnesses);
+            assert_eq!(snapshot(&tree).anchor, s.anchor);
+            // the checkpoints before are still complete
+            let tree = copy(&tree);
+            for (h, s) in snapshots[..=height].iter().enumerate() {
+                for (pos, root) in s.roots.iter() {
+                    assert_eq!(&tree.witness_root_at(*pos, h as u32).unwrap(), root);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replacing_a_checkpoint_keeps_the_removed_witnesses() {
+        let (mut tree, snapshots, _) = blocks();
+        tree.rewind(8).unwrap();
+        // the note at 0 is spent at 9 again
+        tree.remove_witness(0, 9);
+        tree.checkpoint(9, 100);
+        tree.checkpoint(9, 100);
+        tree.rewind(4).unwrap();
+        assert_eq!(witness_bytes(&tree.witnesses), snapshots[4].witnesses);
+    }
+
+    #[test]
+    fn tree_round_trip() {
+        let (tree, _, _) = blocks();
+        let mut data = vec![];
+        tree.write(&mut data).unwrap();
+        let tree2 = MerkleTree::read(&*data, SaplingHasher::default()).unwrap();
+        let mut data2 = vec![];
+        tree2.write(&mut data2).unwrap();
+        assert_eq!(data, data2);
+        assert_eq!(tree2.pos, tree.pos);
+        assert_eq!(snapshot(&tree2).anchor, snapshot(&tree).anchor);
+        assert_eq!(
+            witness_bytes(&tree2.witnesses),
+            witness_bytes(&tree.witnesses)
+        );
+        assert_eq!(tree2.checkpoints.len(), tree.checkpoints.len());
+    }
+
+    #[test]
+    fn read_rejects_invalid_data() {
+        let (tree, _, _) = blocks();
+        let mut data = vec![];
+        tree.write(&mut data).unwrap();
+        let read = |data: &[u8]| MerkleTree::read(data, SaplingHasher::default());
+
+        let mut bad_version = data.clone();
+        bad_version[0] = TREE_VERSION + 1;
+        assert!(read(&bad_version).is_err());
+        assert!(MerkleTree::read(&*data, OrchardHasher::default()).is_err());
+        assert!(read(&data[..data.len() - 1]).is_err());
+        // the nodes must be canonical
+        let mut bad_node = data.clone();
+        bad_node[10..42].fill(0xFF);
+        assert!(read(&bad_node).is_err());
+
+        // a witness at position 3 has 2 siblings and at most 30 fills
+        let mut w = tree.witnesses[0].clone();
+        w.path.pos = 3;
+        w.path.siblings = vec![leaf(0), leaf(1)];
+        w.fills = vec![leaf(2)];
+        let mut data = vec![];
+        w.write(&mut data).unwrap();
+        assert!(Witness::read(&*data, &SaplingHasher::default()).is_ok());
+        let mut bad_siblings = data.clone();
+        bad_siblings[40] = 1;
+        assert!(Witness::read(&*bad_siblings, &SaplingHasher::default()).is_err());
+        let mut bad_fills = data.clone();
+        bad_fills[108] = 31;
+        assert!(Witness::read(&*bad_fills, &SaplingHasher::default()).is_err());
+        let mut bad_value = data.clone();
+        bad_value[8..40].fill(0xFF);
+        assert!(Witness::read(&*bad_value, &SaplingHasher::default()).is_err());
     }
 }
@@ -1,14 +1,27 @@
-use super::{Hasher, Path, ReadWrite, DEPTH};
+use super::hasher::check_nodes;
+use super::{Hash, Hasher, MerkleTree, Path, ReadWrite, DEPTH};
 use anyhow::Result;
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use orchard::tree::MerkleHashOrchard;
 use std::fmt::{Debug, Formatter};
 use std::io::{Read, Write};
+use zcash_encoding::{Optional, Vector};
+use zcash_primitives::merkle_tree::{Hashable, IncrementalWitness, MerklePath};
 
 pub struct Witness<H: Hasher> {
     pub path: Path<H>,
     pub fills: Vec<H::D>,
 }
 
+impl<H: Hasher> Clone for Witness<H> {
+    fn clone(&self) -> Self {
+        Witness {
+            path: self.path.clone(),
+            fills: self.fills.clone(),
+        }
+    }
+}
+
 impl<H: Hasher> Debug for Witness<H> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "path: {:?}", self.path)?;
@@ -24,7 +37,7 @@ impl<H: Hasher> Witness<H> {
         h: &H,
     ) -> (H::D, [H::D; DEPTH]) {
         let mut p = self.path.pos;
-        let mut hash = self.path.value.clone();
+        let mut hash = self.path.value;
         let mut j = 0;
         let mut k = 0;
         let mut edge_used = false;
@@ -38,42 +51,244 @@ impl<H: Hasher> Witness<H> {
                     r
                 } else if !edge_used {
                     edge_used = true;
-                    &edge[i - 1]
+                    if i == 0 {
+                        // the note is the last leaf of the tree
+                        &empty_roots[0]
+                    } else {
+                        &edge[i - 1]
+                    }
                 } else {
                     &empty_roots[i]
                 };
-                path.push(r.clone());
+                path.push(*r);
                 h.combine(i as u8, &hash, r, false)
             } else {
                 let l = &self.path.siblings[j];
-                path.push(l.clone());
+                path.push(*l);
                 let v = h.combine(i as u8, l, &hash, true);
                 j += 1;
                 v
             };
-            p = p / 2;
+            p /= 2;
         }
 
-        (hash.clone(), path.try_into().unwrap())
+        (hash, path.try_into().unwrap())
     }
 
     pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
         self.path.write(&mut w)?;
-        w.write_u8(self.fills.len() as u8).unwrap();
+        w.write_u32::<LE>(self.fills.len() as u32)?;
         for f in self.fills.iter() {
             f.write(&mut w)?;
         }
         Ok(())
     }
 
-    pub fn read<R: Read>(mut r: R) -> Result<Self> {
-        let path = Path::read(&mut r)?;
-        let len = r.read_u8()? as usize;
+    pub fn read<R: Read>(mut r: R, h: &H) -> Result<Self> {
+        let path = Path::read(&mut r, h)?;
+        // at most one fill for every 0 bit of the position
+        let len = r.read_u32::<LE>()? as usize;
+        if len > DEPTH - path.pos.count_ones() as usize {
+            anyhow::bail!("Invalid number of fills {len} at position {}", path.pos);
+        }
         let mut fills = vec![];
         for _ in 0..len {
             let fill = H::D::read(&mut r)?;
             fills.push(fill);
         }
+        check_nodes(h, &fills)?;
         Ok(Self { path, fills })
     }
 }
+
+impl<H: Hasher<D = Hash>> Witness<H> {
+    /// Merkle path of the note to the anchor of the tree with `edge`,
+    /// as used by the sapling spend prover
+    pub fn to_merkle_path<N: Hashable>(
+        &self,
+        empty_roots: &[Hash; DEPTH],
+        edge: &[Hash; DEPTH],
+        h: &H,
+    ) -> Result<MerklePath<N>> {
+        let (_, path) = self.root(empty_roots, edge, h);
+        let auth_path = path
+            .iter()
+            .enumerate()
+            .map(|(i, n)| Ok((N::read(&n[..])?, (self.path.pos >> i) & 1 == 1)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(MerklePath::from_path(auth_path, self.path.pos as u64))
+    }
+
+    /// Merkle path of an orchard note to the anchor of the tree with `edge`
+    pub fn to_orchard_merkle_path(
+        &self,
+        empty_roots: &[Hash; DEPTH],
+        edge: &[Hash; DEPTH],
+        h: &H,
+    ) -> Result<orchard::tree::MerklePath> {
+        let (_, path) = self.root(empty_roots, edge, h);
+        let auth_path = path
+            .iter()
+            .map(|n| {
+                Option::from(MerkleHashOrchard::from_bytes(n))
+                    .ok_or(anyhow::anyhow!("Invalid orchard node"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(orchard::tree::MerklePath::from_parts(
+            self.path.pos as u32,
+            auth_path.try_into().unwrap(),
+        ))
+    }
+
+    /// Write the witness in the `IncrementalWitness` format of zcashd
+    ///
+    /// The subtree to the right of the filled ones is not complete. It has
+    /// the last leaf of `tree`, and its frontier is the frontier of `tree`
+    /// below its depth. It is written as the cursor so that the witness
+    /// can be updated with new notes
+    pub fn write_incremental_witness<W: Write>(
+        &self,
+        tree: &MerkleTree<H>,
+        mut w: W,
+    ) -> Result<()> {
+        // the tree when the note was added
+        let pos = self.path.pos;
+        let mut siblings = self.path.siblings.iter();
+        let (left, right) = if pos & 1 == 0 {
+            (self.path.value, None)
+        } else {
+            (*siblings.next().unwrap(), Some(self.path.value))
+        };
+        let mut parents = vec![];
+        for depth in 1..DEPTH {
+            if pos & (1 << depth) != 0 {
+                parents.resize(depth - 1, None);
+                parents.push(Some(*siblings.next().unwrap()));
+            }
+        }
+        write_commitment_tree(left, right, &parents, &mut w)?;
+
+        // the right siblings, as in Witness::root, up to the first one
+        // that is not filled
+        let mut fills = self.fills.iter();
+        let cursor_depth = (0..DEPTH).find(|i| (pos >> i) & 1 == 0 && fills.next().is_none());
+        Vector::write(&mut w, &self.fills, |w, n| w.write_all(n))?;
+
+        let cursor = match (cursor_depth, tree.frontier_nodes()?) {
+            // the subtree is empty if the tree ends before it
+            (Some(depth), Some(nodes)) if tree.pos > ((pos >> depth) | 1) << depth => {
+                let mut parents = vec![];
+                for (d, ommer) in nodes.ommers.iter().take_while(|(d, _)| *d < depth) {
+                    parents.resize(d - 1, None);
+                    parents.push(Some(*ommer));
+                }
+                Some((nodes.left, nodes.right, parents))
+            }
+            _ => None,
+        };
+        Optional::write(&mut w, cursor, |w, (left, right, parents)| {
+            write_commitment_tree(left, right, &parents, w)
+        })?;
+        Ok(())
+    }
+
+    pub fn to_incremental_witness<N: Hashable>(
+        &self,
+        tree: &MerkleTree<H>,
+    ) -> Result<IncrementalWitness<N>> {
+        let mut data = vec![];
+        self.write_incremental_witness(tree, &mut data)?;
+        Ok(IncrementalWitness::read(&*data)?)
+    }
+}
+
+/// Write a `CommitmentTree` in the format of zcashd
+fn write_commitment_tree<W: Write>(
+    left: Hash,
+    right: Option<Hash>,
+    parents: &[Option<Hash>],
+    mut w: W,
+) -> std::io::Result<()> {
+    Optional::write(&mut w, Some(left), |w, n| w.write_all(&n))?;
+    Optional::write(&mut w, right, |w, n| w.write_all(&n))?;
+    Vector::write(&mut w, parents, |w, p| {
+        Optional::write(w, *p, |w, n| w.write_all(&n))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warp::hasher::{OrchardHasher, SaplingHasher};
+    use crate::warp::testing::leaf;
+    use crate::warp::{empty_roots, MerkleTree};
+    use zcash_primitives::merkle_tree::CommitmentTree;
+    use zcash_primitives::sapling::Node;
+
+    fn node<N: Hashable>(h: &Hash) -> N {
+        N::read(&h[..]).unwrap()
+    }
+
+    /// Witnesses of a warp tree and of an `IncrementalWitness` over
+    /// the same leaves give the same Merkle paths. The witnesses converted
+    /// to `IncrementalWitness` still match after more leaves are appended
+    fn check_witnesses<H: Hasher<D = Hash>, N: Hashable + PartialEq + std::fmt::Debug>() {
+        let h = H::default();
+        let er = empty_roots(&h);
+        let witnessed = [0, 5, 6, 13, 16, 31, 32, 40];
+        let mut tree = MerkleTree::empty(H::default());
+        let mut ct = CommitmentTree::<N>::empty();
+        let mut iws: Vec<(usize, IncrementalWitness<N>)> = vec![];
+        let mut converted: Vec<IncrementalWitness<N>> = vec![];
+        let mut size = 0;
+        for len in [1, 3, 2, 7, 1, 1, 12, 4, 9, 5, 1, 16, 2] {
+            let nodes: Vec<_> = (size..size + len)
+                .map(|i| (leaf(i), witnessed.contains(&i)))
+                .collect();
+            tree.add_nodes(0, 0, &nodes);
+            for (i, (cm, is_witnessed)) in nodes.iter().enumerate() {
+                ct.append(node(cm)).unwrap();
+                for (_, iw) in iws.iter_mut() {
+                    iw.append(node(cm)).unwrap();
+                }
+                for iw in converted.iter_mut() {
+                    iw.append(node(cm)).unwrap();
+                }
+                if *is_witnessed {
+                    iws.push((size + i, IncrementalWitness::from_tree(&ct)));
+                }
+            }
+            size += len;
+
+            let edge = tree.edge(&er);
+            let anchor = ct.root();
+            for (iw, (pos, expected)) in converted.iter().zip(iws.iter()) {
+                assert_eq!(iw.root(), anchor, "root of {pos} after appending");
+                assert_eq!(iw.path(), expected.path(), "path of {pos} after appending");
+            }
+            assert_eq!(tree.witnesses.len(), iws.len());
+            converted.clear();
+            for (w, (pos, iw)) in tree.witnesses.iter().zip(iws.iter()) {
+                assert_eq!(w.path.pos, *pos);
+                let expected = iw.path().unwrap();
+                let path = w.to_merkle_path::<N>(&er, &edge, &h).unwrap();
+                assert_eq!(path.root(node(&leaf(*pos))), anchor);
+                assert_eq!(path, expected, "path of {pos} in a tree of size {size}");
+                let iw2 = w.to_incremental_witness::<N>(&tree).unwrap();
+                assert_eq!(iw2.root(), anchor);
+                assert_eq!(iw2.path().unwrap(), expected);
+                converted.push(iw2);
+            }
+        }
+    }
+
+    #[test]
+    fn sapling_witnesses_match_incremental_witnesses() {
+        check_witnesses::<SaplingHasher, Node>();
+    }
+
+    #[test]
+    fn orchard_witnesses_match_incremental_witnesses() {
+        check_witnesses::<OrchardHasher, MerkleHashOrchard>();
+    }
+}
@@ -16,6 +16,103 @@ typedef void *DartPostCObjectFnType;
 
 #define DEPTH 32
 
-void dart_post_cobject(DartPostCObjectFnType ptr);
+/**
+ * Number of consecutive transparent addresses without transactions after
+ * which the scan of a chain of addresses stops (BIP-44 gap limit)
+ */
+#define TRANSPARENT_GAP_LIMIT 20
 
-uint64_t full_scan(char *url, char *fvk, int64_t port);
+/**
+ * The call succeeded
+ */
+#define WARP2_OK 0
+
+/**
+ * The scan was stopped by [cancel_scan]
+ */
+#define WARP2_CANCELLED 1
+
+/**
+ * The call failed, [warp2_last_error] has the reason
+ */
+#define WARP2_ERROR -1
+
+/**
+ * The call panicked, [warp2_last_error] has the panic message
+ */
+#define WARP2_PANIC -2
+
+/**
+ * # Safety
+ * `ptr` must be the `NativeApi.postCObject` function pointer handed over by Dart
+ */
+int32_t dart_post_cobject(DartPostCObjectFnType ptr);
+
+/**
+ * Create the handle of the next scan
+ *
+ * [cancel_scan], [pause_scan] and [resume_scan] apply to the scan as soon
+ * as this returns, even if [full_scan] has not started yet. Without it,
+ * [full_scan] starts with a new handle
+ */
+int32_t new_scan(void);
+
+/**
+ * Scan the data file at `url` with the viewing key `fvk` and store the
+ * balance in `balance`
+ *
+ * The scan is controlled by the handle created by [new_scan], or by a new
+ * one if the handle was already used by a previous scan. It returns
+ * [WARP2_CANCELLED] if it was cancelled, with the balance at the height
+ * where it stopped
+ *
+ * If `state_file` is not NULL, the scan resumes from the state saved there
+ * and saves it after every chunk of blocks, so that a cancelled scan
+ * continues where it stopped
+ *
+ * The last `max_checkpoints` blocks are kept as checkpoints, i.e. the
+ * maximum depth of a reorg and of an anchor. 0 keeps the window of the
+ * state file, 100 blocks for a new state
+ *
+ * The progress is posted to `port` after every chunk of blocks as a list of
+ * integers: height, target height, block bytes, blocks, outputs, notes, spends,
+ * elapsed and ETA in ms (-1 when unknown). If the scan fails, the error
+ * message is posted as a string
+ *
+ * # Safety
+ * `url` and `fvk` must be valid NUL terminated C strings, `state_file`
+ * must be NULL or a valid NUL terminated C string and `balance`
+ * must be NULL or point to a writable u64
+ */
+int32_t full_scan(const char *url,
+                  const char *fvk,
+                  const char *state_file,
+                  int64_t port,
+                  uint32_t max_checkpoints,
+                  uint64_t *balance);
+
+/**
+ * Stop the running scan at the end of its current chunk of blocks.
+ * [full_scan] then stores the balance at that height and returns
+ * [WARP2_CANCELLED]
+ */
+int32_t cancel_scan(void);
+
+/**
+ * Pause the running scan at the end of its current chunk of blocks
+ */
+int32_t pause_scan(void);
+
+int32_t resume_scan(void);
+
+/**
+ * Message of the last call that did not return [WARP2_OK], NULL if none.
+ * The string must be released with [warp2_free_string]
+ */
+char *warp2_last_error(void);
+
+/**
+ * # Safety
+ * `s` must be NULL or a string returned by [warp2_last_error]
+ */
+void warp2_free_string(char *s);